@@ -0,0 +1,280 @@
+//! D-Bus interface of the NixOS updater daemon and typed client bindings for it.
+//!
+//! The interface is documented in `de.afuchs.NixOSUpdater.xml`.
+
+pub use dbus;
+
+use dbus::arg::{PropMap, RefArg, Variant};
+use dbus::blocking::{BlockingSender, Connection, Proxy};
+use dbus::blocking::stdintf::org_freedesktop_dbus::Properties;
+use std::ops::Deref;
+use std::time::Duration;
+
+pub const NAME: &str = "de.afuchs.NixOSUpdater";
+pub const PATH: &str = "/de/afuchs/NixOSUpdater";
+/// raised on every incompatible change of the interface
+pub const INTERFACE_VERSION: u32 = 1;
+/// introspection data of the interface, the daemon serves exactly this
+pub const INTERFACE_XML: &str = include_str!("../de.afuchs.NixOSUpdater.xml");
+/// long enough for polkit to ask the user for a password
+pub const TIMEOUT: Duration = Duration::from_secs(120);
+
+/// derivations built, derivations to build, bytes downloaded, bytes to download
+/// and the current build or an empty string
+pub type Progress = (u64, u64, u64, u64, String);
+/// name, old versions, new versions and size change in bytes
+pub type PackageChange = (String, Vec<String>, Vec<String>, i64);
+/// name, old and new revision, old and new last modification as seconds since the epoch
+/// and URL, unknown values are empty or 0
+pub type InputChange = (String, String, String, i64, i64, String);
+/// number, store path, creation as seconds since the epoch, NixOS version, kernel version
+/// and whether it is the current one
+pub type Generation = (u32, String, i64, String, String, bool);
+/// id, last write to the log as seconds since the epoch and size of the log in bytes
+pub type RunInfo = (String, i64, u64);
+/// run id, start and end as seconds since the epoch, trigger, target, UpdateState the run ended in,
+/// last ProcessState, old and new system, changed inputs like [`InputChange`] and error message,
+/// unknown values are empty or 0
+pub type HistoryEntry = (String, i64, i64, String, String, String, String, String, String, Vec<InputChange>, String);
+
+/// Names of the errors the methods fail with, besides org.freedesktop.DBus.Error.AccessDenied
+/// and org.freedesktop.DBus.Error.InvalidArgs.
+pub mod error {
+	/// an update is already in progress
+	pub const BUSY: &str = "de.afuchs.NixOSUpdater.Error.Busy";
+	/// no update is in progress
+	pub const NOT_RUNNING: &str = "de.afuchs.NixOSUpdater.Error.NotRunning";
+	/// there is no kept build
+	pub const NOTHING_BUILT: &str = "de.afuchs.NixOSUpdater.Error.NothingBuilt";
+	/// the property has no value in the current UpdateState
+	pub const NO_VALUE: &str = "de.afuchs.NixOSUpdater.Error.NoValue";
+	/// the config file could not be loaded
+	pub const INVALID_CONFIG: &str = "de.afuchs.NixOSUpdater.Error.InvalidConfig";
+	/// polkit could not be asked whether the caller is authorized
+	pub const AUTHORIZATION_FAILED: &str = "de.afuchs.NixOSUpdater.Error.AuthorizationFailed";
+	/// anything else, the message tells what failed
+	pub const FAILED: &str = "de.afuchs.NixOSUpdater.Error.Failed";
+}
+
+/// Why the last update failed, the LastError property.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ErrorDetails {
+	/// value of the UpdateError property
+	pub code: String,
+	/// ProcessState the update failed in, empty if it failed before reporting one
+	pub stage: String,
+	pub message: String,
+	/// exit status of the command that failed
+	pub exit_status: Option<i32>,
+	/// last lines the failed command wrote to stderr
+	pub stderr: Vec<String>,
+	/// derivation that failed to build
+	pub derivation: Option<String>,
+	/// run whose log tells more, see [`NixOSUpdater::get_run_log`]
+	pub run_id: Option<String>,
+}
+
+impl ErrorDetails {
+	pub fn to_dict(&self) -> PropMap {
+		let mut dict = PropMap::new();
+		let mut insert = |key: &str, value: Box<dyn RefArg>| {
+			dict.insert(key.to_string(), Variant(value));
+		};
+		insert("code", Box::new(self.code.clone()));
+		insert("stage", Box::new(self.stage.clone()));
+		insert("message", Box::new(self.message.clone()));
+		insert("stderr", Box::new(self.stderr.clone()));
+		if let Some(status) = self.exit_status {
+			insert("exit_status", Box::new(status));
+		}
+		if let Some(drv) = &self.derivation {
+			insert("derivation", Box::new(drv.clone()));
+		}
+		if let Some(id) = &self.run_id {
+			insert("run_id", Box::new(id.clone()));
+		}
+		dict
+	}
+
+	/// None for the empty dict the daemon reports while there is no error.
+	pub fn from_dict(dict: &PropMap) -> Option<Self> {
+		if dict.is_empty() {
+			return None;
+		}
+		let string = |key: &str| dict.get(key).and_then(|v| v.0.as_str()).map(str::to_string);
+		Some(Self {
+			code: string("code").unwrap_or_default(),
+			stage: string("stage").unwrap_or_default(),
+			message: string("message").unwrap_or_default(),
+			exit_status: dict.get("exit_status").and_then(|v| v.0.as_i64()).map(|s| s as i32),
+			stderr: dict.get("stderr").and_then(|v| v.0.as_iter())
+				.map(|lines| lines.filter_map(|l| l.as_str().map(str::to_string)).collect())
+				.unwrap_or_default(),
+			derivation: string("derivation"),
+			run_id: string("run_id"),
+		})
+	}
+}
+
+/// Proxy for the daemon on `con`.
+pub fn proxy(con: &Connection) -> Proxy<'_, &Connection> {
+	con.with_proxy(NAME, PATH, TIMEOUT)
+}
+
+/// Methods and properties of the de.afuchs.NixOSUpdater interface.
+pub trait NixOSUpdater {
+	fn check(&self) -> Result<(), dbus::Error>;
+	fn build(&self) -> Result<(), dbus::Error>;
+	fn switch(&self) -> Result<(), dbus::Error>;
+	fn set_boot(&self) -> Result<(), dbus::Error>;
+	fn reboot(&self) -> Result<(), dbus::Error>;
+	fn build_update(&self) -> Result<(), dbus::Error>;
+	fn apply_update(&self, target: &str) -> Result<(), dbus::Error>;
+	fn cancel(&self) -> Result<(), dbus::Error>;
+	fn get_change_summary(&self) -> Result<(Vec<PackageChange>, i64), dbus::Error>;
+	fn list_generations(&self) -> Result<Vec<Generation>, dbus::Error>;
+	fn rollback_to(&self, generation: u32, mode: &str) -> Result<(), dbus::Error>;
+	fn preview_garbage_collection(&self) -> Result<Vec<u32>, dbus::Error>;
+	fn reload_config(&self) -> Result<(), dbus::Error>;
+	fn list_runs(&self) -> Result<Vec<RunInfo>, dbus::Error>;
+	fn get_run_log(&self, run_id: &str, offset: u64) -> Result<(String, u64), dbus::Error>;
+	fn get_derivation_log(&self, drv: &str) -> Result<String, dbus::Error>;
+	fn get_history(&self, limit: u32) -> Result<Vec<HistoryEntry>, dbus::Error>;
+
+	fn version(&self) -> Result<String, dbus::Error>;
+	fn interface_version(&self) -> Result<u32, dbus::Error>;
+	fn update_state(&self) -> Result<String, dbus::Error>;
+	fn process_state(&self) -> Result<String, dbus::Error>;
+	fn update_error(&self) -> Result<String, dbus::Error>;
+	fn last_error(&self) -> Result<Option<ErrorDetails>, dbus::Error>;
+	fn requires_reboot(&self) -> Result<bool, dbus::Error>;
+	fn reboot_reasons(&self) -> Result<Vec<String>, dbus::Error>;
+	fn build_progress(&self) -> Result<Progress, dbus::Error>;
+	fn input_changes(&self) -> Result<Vec<InputChange>, dbus::Error>;
+	fn held_inputs(&self) -> Result<Vec<String>, dbus::Error>;
+	fn next_scheduled_run(&self) -> Result<i64, dbus::Error>;
+	fn last_run(&self) -> Result<i64, dbus::Error>;
+}
+
+impl<'a, T: BlockingSender, C: Deref<Target = T>> NixOSUpdater for Proxy<'a, C> {
+	fn check(&self) -> Result<(), dbus::Error> {
+		self.method_call(NAME, "Check", ())
+	}
+
+	fn build(&self) -> Result<(), dbus::Error> {
+		self.method_call(NAME, "Build", ())
+	}
+
+	fn switch(&self) -> Result<(), dbus::Error> {
+		self.method_call(NAME, "Switch", ())
+	}
+
+	fn set_boot(&self) -> Result<(), dbus::Error> {
+		self.method_call(NAME, "SetBoot", ())
+	}
+
+	fn reboot(&self) -> Result<(), dbus::Error> {
+		self.method_call(NAME, "Reboot", ())
+	}
+
+	fn build_update(&self) -> Result<(), dbus::Error> {
+		self.method_call(NAME, "BuildUpdate", ())
+	}
+
+	fn apply_update(&self, target: &str) -> Result<(), dbus::Error> {
+		self.method_call(NAME, "ApplyUpdate", (target,))
+	}
+
+	fn cancel(&self) -> Result<(), dbus::Error> {
+		self.method_call(NAME, "Cancel", ())
+	}
+
+	fn get_change_summary(&self) -> Result<(Vec<PackageChange>, i64), dbus::Error> {
+		self.method_call(NAME, "GetChangeSummary", ())
+	}
+
+	fn list_generations(&self) -> Result<Vec<Generation>, dbus::Error> {
+		self.method_call(NAME, "ListGenerations", ()).map(|(g,)| g)
+	}
+
+	fn rollback_to(&self, generation: u32, mode: &str) -> Result<(), dbus::Error> {
+		self.method_call(NAME, "RollbackTo", (generation, mode))
+	}
+
+	fn preview_garbage_collection(&self) -> Result<Vec<u32>, dbus::Error> {
+		self.method_call(NAME, "PreviewGarbageCollection", ()).map(|(g,)| g)
+	}
+
+	fn reload_config(&self) -> Result<(), dbus::Error> {
+		self.method_call(NAME, "ReloadConfig", ())
+	}
+
+	fn list_runs(&self) -> Result<Vec<RunInfo>, dbus::Error> {
+		self.method_call(NAME, "ListRuns", ()).map(|(r,)| r)
+	}
+
+	fn get_run_log(&self, run_id: &str, offset: u64) -> Result<(String, u64), dbus::Error> {
+		self.method_call(NAME, "GetRunLog", (run_id, offset))
+	}
+
+	fn get_derivation_log(&self, drv: &str) -> Result<String, dbus::Error> {
+		self.method_call(NAME, "GetDerivationLog", (drv,)).map(|(l,)| l)
+	}
+
+	fn get_history(&self, limit: u32) -> Result<Vec<HistoryEntry>, dbus::Error> {
+		self.method_call(NAME, "GetHistory", (limit,)).map(|(h,)| h)
+	}
+
+	fn version(&self) -> Result<String, dbus::Error> {
+		self.get(NAME, "Version")
+	}
+
+	fn interface_version(&self) -> Result<u32, dbus::Error> {
+		self.get(NAME, "InterfaceVersion")
+	}
+
+	fn update_state(&self) -> Result<String, dbus::Error> {
+		self.get(NAME, "UpdateState")
+	}
+
+	fn process_state(&self) -> Result<String, dbus::Error> {
+		self.get(NAME, "ProcessState")
+	}
+
+	fn update_error(&self) -> Result<String, dbus::Error> {
+		self.get(NAME, "UpdateError")
+	}
+
+	fn last_error(&self) -> Result<Option<ErrorDetails>, dbus::Error> {
+		let dict: PropMap = self.get(NAME, "LastError")?;
+		Ok(ErrorDetails::from_dict(&dict))
+	}
+
+	fn requires_reboot(&self) -> Result<bool, dbus::Error> {
+		self.get(NAME, "RequiresReboot")
+	}
+
+	fn reboot_reasons(&self) -> Result<Vec<String>, dbus::Error> {
+		self.get(NAME, "RebootReasons")
+	}
+
+	fn build_progress(&self) -> Result<Progress, dbus::Error> {
+		self.get(NAME, "BuildProgress")
+	}
+
+	fn input_changes(&self) -> Result<Vec<InputChange>, dbus::Error> {
+		self.get(NAME, "InputChanges")
+	}
+
+	fn held_inputs(&self) -> Result<Vec<String>, dbus::Error> {
+		self.get(NAME, "HeldInputs")
+	}
+
+	fn next_scheduled_run(&self) -> Result<i64, dbus::Error> {
+		self.get(NAME, "NextScheduledRun")
+	}
+
+	fn last_run(&self) -> Result<i64, dbus::Error> {
+		self.get(NAME, "LastRun")
+	}
+}
@@ -1,11 +1,67 @@
 use clap::{Parser, Subcommand};
+use std::path::PathBuf;
+use crate::config;
 
 #[derive(Subcommand, Debug)]
 pub enum Command {
-	Daemon,
+	Daemon {
+		#[arg(short, long, default_value = config::DEFAULT_PATH)]
+		config: PathBuf,
+	},
 	Status,
+	/// run an update to the target in the config file
 	BuildUpdate,
-	DaemonDebug,
+	/// look for an update without building it
+	Check,
+	/// build an update to apply it later
+	Build,
+	/// switch to the update, building it first if needed
+	Switch,
+	/// make the update the boot default, building it first if needed
+	SetBoot,
+	/// reboot into the update, building it first if needed
+	Reboot,
+	/// apply an update built earlier
+	ApplyUpdate {
+		#[arg(value_parser = ["switch", "set_boot", "reboot"])]
+		target: String,
+	},
+	/// show which packages the built update changes
+	Diff,
+	/// list the generations of the system profile
+	Generations,
+	/// switch back to an earlier generation
+	Rollback {
+		generation: u32,
+		#[arg(long, default_value = "switch", value_parser = ["switch", "set_boot", "reboot"])]
+		mode: String,
+	},
+	/// cancel the running update
+	Cancel,
+	/// list the updates whose log is kept
+	Logs,
+	/// print the log of an update, the newest one by default
+	Log {
+		run_id: Option<String>,
+		/// keep printing while the update is in progress
+		#[arg(short, long)]
+		follow: bool,
+	},
+	/// print the build log of a derivation, e.g. the one an update failed to build
+	BuildLog {
+		drv: String,
+	},
+	/// list past updates, the newest first
+	History {
+		/// number of updates to list, 0 for all
+		#[arg(short = 'n', long, default_value_t = 10)]
+		limit: u32,
+	},
+	ReloadConfig,
+	DaemonDebug {
+		#[arg(short, long, default_value = config::DEFAULT_PATH)]
+		config: PathBuf,
+	},
 }
 
 #[derive(Parser, Debug)]
@@ -1,7 +1,7 @@
-use crate::consts;
-
 use dbus::blocking::{Connection, Proxy};
-use dbus::blocking::stdintf::org_freedesktop_dbus::Properties;
+use nixos_updater_interface::{self as interface, NixOSUpdater};
+use chrono::{DateTime, Local};
+use std::io::Write;
 use std::time::Duration;
 
 pub struct Client {
@@ -10,25 +10,214 @@ pub struct Client {
 
 impl Client {
     pub fn new() -> Result<Self, dbus::Error> {
-        let con = Connection::new_session()?;
+        let con = Connection::new_system()?;
         Ok(Self { con })
     }
 
     fn get_proxy(&self) -> Proxy<'_, &'_ Connection> {
-        self.con.with_proxy(consts::NAME, "/de/afuchs/NixOSUpdater", Duration::from_millis(5000))
+        interface::proxy(&self.con)
     }
 
     pub fn print_status(&self) -> anyhow::Result<()> {
-        let status: String = self.get_proxy().get(consts::NAME, "UpdateState")?;
+        let proxy = self.get_proxy();
+        let status = proxy.update_state()?;
         println!("UpdateState={}", status);
         if status == "processing" {
-            let status: String = self.get_proxy().get(consts::NAME, "ProcessState")?;
-            println!("ProcessState={}", status);
+            println!("ProcessState={}", proxy.process_state()?);
+            Self::print_progress(proxy.build_progress()?);
+        }
+        if status == "error" {
+            println!("UpdateError={}", proxy.update_error()?);
+            if let Some(details) = proxy.last_error()? {
+                Self::print_error_details(&details);
+            }
+        }
+        if status == "ready" {
+            println!("RequiresReboot={}", proxy.requires_reboot()?);
+            for reason in proxy.reboot_reasons()? {
+                println!("RebootReason={}", reason);
+            }
+        }
+        let changes = proxy.input_changes()?;
+        for (name, old_rev, new_rev, old_modified, new_modified, _url) in changes {
+            println!("InputChange={}: {} → {} ({} → {})", name, Self::short_rev(&old_rev), Self::short_rev(&new_rev),
+                Self::format_date(old_modified), Self::format_date(new_modified));
+        }
+        let held = proxy.held_inputs()?;
+        if ! held.is_empty() {
+            println!("HeldInputs={}", held.join(","));
+        }
+        println!("NextScheduledRun={}", Self::format_timestamp(proxy.next_scheduled_run()?));
+        println!("LastRun={}", Self::format_timestamp(proxy.last_run()?));
+        Ok(())
+    }
+
+    fn print_error_details(details: &interface::ErrorDetails) {
+        if ! details.stage.is_empty() {
+            println!("FailedStage={}", details.stage);
+        }
+        println!("ErrorMessage={}", details.message);
+        if let Some(status) = details.exit_status {
+            println!("ExitStatus={}", status);
+        }
+        if let Some(drv) = &details.derivation {
+            println!("FailedDerivation={}", drv);
+        }
+        for line in &details.stderr {
+            println!("Stderr={}", line);
+        }
+        if let Some(id) = &details.run_id {
+            println!("RunId={}", id);
+        }
+    }
+
+    fn print_progress((done, expected, downloaded, download_size, current): interface::Progress) {
+        const WIDTH: u64 = 30;
+        if let Some(filled) = (done.min(expected) * WIDTH).checked_div(expected) {
+            let filled = filled as usize;
+            println!("Builds=[{}{}] {}/{}", "#".repeat(filled), ".".repeat(WIDTH as usize - filled), done, expected);
+        }
+        if download_size > 0 {
+            const MIB: f64 = 1024.0 * 1024.0;
+            println!("Downloaded={:.1}/{:.1} MiB", downloaded as f64 / MIB, download_size as f64 / MIB);
+        }
+        if ! current.is_empty() {
+            println!("CurrentBuild={}", current);
+        }
+    }
+
+    fn short_rev(rev: &str) -> &str {
+        if rev.is_empty() { "∅" } else { &rev[..rev.len().min(7)] }
+    }
+
+    fn format_date(t: i64) -> String {
+        match DateTime::from_timestamp(t, 0) {
+            Some(t) if t.timestamp() != 0 => t.format("%Y-%m-%d").to_string(),
+            _ => "∅".to_string(),
+        }
+    }
+
+    fn format_timestamp(t: i64) -> String {
+        match DateTime::from_timestamp(t, 0) {
+            Some(t) if t.timestamp() != 0 => t.with_timezone(&Local).to_rfc2822(),
+            _ => "never".to_string(),
+        }
+    }
+
+    /// Prints what the built update changes, in the format of `nix store diff-closures`.
+    pub fn print_change_summary(&self) -> anyhow::Result<()> {
+        let (changes, size_delta) = self.get_proxy().get_change_summary()?;
+        let versions = |v: &[String]| if v.is_empty() { "∅".to_string() } else { v.join(", ") };
+        for (name, old, new, size) in changes {
+            let mut line = format!("{}: ", name);
+            if old != new {
+                line += &format!("{} → {}", versions(&old), versions(&new));
+                if size != 0 {
+                    line += ", ";
+                }
+            }
+            if size != 0 {
+                line += &format!("{:+.1} KiB", size as f64 / 1024.0);
+            }
+            println!("{}", line);
         }
+        println!("Total: {:+.1} KiB", size_delta as f64 / 1024.0);
         Ok(())
     }
 
-    pub fn build_update(&self) -> anyhow::Result<()> {
-        todo!()
+    /// Prints one line per generation, the current one marked with *.
+    pub fn print_generations(&self) -> anyhow::Result<()> {
+        let generations = self.get_proxy().list_generations()?;
+        let or_unknown = |s: &str| if s.is_empty() { "∅".to_string() } else { s.to_string() };
+        for (number, _path, created, label, kernel, current) in generations {
+            println!("{}{:>4}  {}  NixOS {}  Linux {}", if current { "*" } else { " " }, number,
+                Self::format_date(created), or_unknown(&label), or_unknown(&kernel));
+        }
+        Ok(())
+    }
+
+    /// Prints one line per run whose log is kept, the newest first.
+    pub fn print_runs(&self) -> anyhow::Result<()> {
+        for (id, modified, size) in self.get_proxy().list_runs()? {
+            println!("{}  {}  {:.1} KiB", id, Self::format_timestamp(modified), size as f64 / 1024.0);
+        }
+        Ok(())
+    }
+
+    /// Prints the log of run `run_id`, or of the newest run if None. With `follow`, keeps
+    /// printing what is written to the log while an update is in progress.
+    pub fn print_run_log(&self, run_id: Option<&str>, follow: bool) -> anyhow::Result<()> {
+        let proxy = self.get_proxy();
+        let id = match run_id {
+            Some(id) => id.to_string(),
+            None => match proxy.list_runs()?.into_iter().next() {
+                Some((id, _, _)) => id,
+                None => anyhow::bail!("no run log is kept"),
+            },
+        };
+        let mut offset = 0;
+        loop {
+            let (data, next) = proxy.get_run_log(&id, offset)?;
+            if data.is_empty() {
+                if ! follow || proxy.update_state()? != "processing" {
+                    break;
+                }
+                std::thread::sleep(Duration::from_secs(1));
+            }
+            print!("{}", data);
+            std::io::stdout().flush()?;
+            offset = next;
+        }
+        Ok(())
+    }
+
+    /// Prints one line per past update and indented lines with what it changed and why it failed.
+    pub fn print_history(&self, limit: u32) -> anyhow::Result<()> {
+        let or_unknown = |s: &str| if s.is_empty() { "∅".to_string() } else { s.to_string() };
+        for (id, started, _finished, trigger, target, state, stage, old, new, changes, error) in self.get_proxy().get_history(limit)? {
+            let stage = if stage.is_empty() { String::new() } else { format!(" after {}", stage) };
+            println!("{}  {}  {} {} → {}{}", id, Self::format_timestamp(started), trigger, target, state, stage);
+            if old != new {
+                println!("    System={} → {}", or_unknown(&old), or_unknown(&new));
+            }
+            for (name, old_rev, new_rev, _, _, _) in changes {
+                println!("    InputChange={}: {} → {}", name, Self::short_rev(&old_rev), Self::short_rev(&new_rev));
+            }
+            if ! error.is_empty() {
+                println!("    Error={}", error);
+            }
+        }
+        Ok(())
+    }
+
+    pub fn print_derivation_log(&self, drv: &str) -> anyhow::Result<()> {
+        print!("{}", self.get_proxy().get_derivation_log(drv)?);
+        Ok(())
+    }
+
+    pub fn rollback(&self, generation: u32, mode: &str) -> anyhow::Result<()> {
+        self.get_proxy().rollback_to(generation, mode)?;
+        self.print_status()
+    }
+
+    pub fn cancel(&self) -> anyhow::Result<()> {
+        self.get_proxy().cancel()?;
+        Ok(())
+    }
+
+    pub fn reload_config(&self) -> anyhow::Result<()> {
+        self.get_proxy().reload_config()?;
+        Ok(())
+    }
+
+    /// Starts an update with `start` and prints the state it is in.
+    pub fn start(&self, start: impl FnOnce(&Proxy<'_, &'_ Connection>) -> Result<(), dbus::Error>) -> anyhow::Result<()> {
+        start(&self.get_proxy())?;
+        self.print_status()
+    }
+
+    pub fn apply_update(&self, target: &str) -> anyhow::Result<()> {
+        self.get_proxy().apply_update(target)?;
+        self.print_status()
     }
 }
@@ -0,0 +1,591 @@
+use crate::errors::*;
+use crate::daemon::RunTo;
+use crate::nix::Profile;
+use crate::nix::flake::{FlakeConfig, InputPolicy, LockMode, LockPolicy};
+use crate::nix::channel::ChannelConfig;
+use crate::nix::prebuilt::{ClosureSource, PrebuiltConfig};
+use crate::nix::verify::TrustPolicy;
+use crate::nix::gc::RetentionPolicy;
+use crate::nix::plan::SpaceCheck;
+use crate::scheduler::Schedule;
+use crate::git::{CommitOptions, Identity};
+use crate::health::HealthChecks;
+use crate::trial::TrialBoot;
+use crate::consts;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use std::fs;
+
+pub const DEFAULT_PATH: &str = "/etc/nixos-updater/config.toml";
+
+/// Config file as written by the user, see [`Config`] for the checked version.
+#[derive(Debug, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ConfigFile {
+	flake: Option<String>,
+	config_name: Option<String>,
+	channel: Option<String>,
+	nixos_config: Option<PathBuf>,
+	prebuilt: Option<PrebuiltFile>,
+	profile: Option<PathBuf>,
+	target: Option<RunTo>,
+	schedule: Option<ScheduleFile>,
+	inputs: Option<InputsFile>,
+	lock_file: Option<LockFileFile>,
+	trust: Option<TrustFile>,
+	health_check: Option<HealthCheckFile>,
+	trial_boot: Option<TrialBootFile>,
+	gc: Option<GcFile>,
+	disk_space: Option<DiskSpaceFile>,
+}
+
+#[derive(Debug, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+struct DiskSpaceFile {
+	store_reserve_mib: Option<u64>,
+	boot_reserve_mib: Option<u64>,
+	#[serde(default)]
+	collect_garbage: bool,
+}
+
+#[derive(Debug, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+struct GcFile {
+	keep: Option<usize>,
+	keep_days: Option<u32>,
+	#[serde(default)]
+	pinned: Vec<u32>,
+	/// bytes
+	max_freed: Option<u64>,
+}
+
+#[derive(Debug, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+struct TrialBootFile {
+	deadline_sec: Option<u64>,
+	max_attempts: Option<u32>,
+}
+
+#[derive(Debug, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+struct HealthCheckFile {
+	#[serde(default)]
+	units: Vec<String>,
+	command: Option<Vec<String>>,
+	#[serde(default)]
+	failed_units: bool,
+	reachable: Option<String>,
+	timeout_sec: Option<u64>,
+}
+
+#[derive(Debug, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+struct TrustFile {
+	public_keys: Vec<String>,
+	sigs_needed: Option<u32>,
+}
+
+#[derive(Debug, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+struct PrebuiltFile {
+	/// http(s) url of a file containing the store path
+	url: Option<String>,
+	/// local file containing the store path
+	file: Option<PathBuf>,
+	/// binary cache publishing the store path as `<cache>/<config_name>`
+	cache: Option<String>,
+	#[serde(default)]
+	substituters: Vec<String>,
+}
+
+#[derive(Debug, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+struct LockFileFile {
+	mode: Option<LockMode>,
+	#[serde(default)]
+	commit: bool,
+	author_name: Option<String>,
+	author_email: Option<String>,
+	#[serde(default)]
+	signoff: bool,
+	/// git remote to push commits to
+	push: Option<String>,
+}
+
+#[derive(Debug, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+struct InputsFile {
+	update: Option<Vec<String>>,
+	#[serde(default)]
+	pinned: Vec<String>,
+	#[serde(default)]
+	follow: BTreeMap<String, String>,
+}
+
+#[derive(Debug, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ScheduleFile {
+	on_calendar: String,
+	randomized_delay_sec: Option<u64>,
+	target: Option<RunTo>,
+}
+
+/// Where the system configuration comes from.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Source {
+	/// `flake` in the config file, e.g. /etc/nixos, with the nixosConfigurations
+	/// attribute `config_name` that defaults to the hostname
+	Flake(FlakeConfig),
+	/// `channel` in the config file together with `nixos_config`
+	Channel(ChannelConfig),
+	/// `prebuilt` table in the config file, a system built by CI
+	Prebuilt(PrebuiltConfig),
+}
+
+#[derive(Debug, Clone)]
+pub struct Config {
+	pub source: Source,
+	/// system profile whose current generation is compared to new builds
+	pub profile: PathBuf,
+	/// how far an update triggered without explicit target is taken
+	pub target: RunTo,
+	/// automatic updates, None if they are disabled
+	pub schedule: Option<Schedule>,
+	/// signatures a new system needs before it is switched to, None to not check them
+	pub trust: Option<TrustPolicy>,
+	/// checks after switching that roll back a broken system, None to not check
+	pub health_check: Option<HealthChecks>,
+	/// confirmation of rebooted systems with `health_check`, None to keep any system that boots
+	pub trial_boot: Option<TrialBoot>,
+	/// generations to delete after switching, None to keep all
+	pub gc: Option<RetentionPolicy>,
+	/// free space required before building, None to not check
+	pub disk_space: Option<SpaceCheck>,
+}
+
+impl Config {
+	pub fn load(path: &Path) -> Result<Self, ConfigError> {
+		let text = fs::read_to_string(path)
+			.map_err(|e| ConfigError::IOError(path.display().to_string(), e))?;
+		Self::parse(&text)
+	}
+
+	pub fn parse(text: &str) -> Result<Self, ConfigError> {
+		let file: ConfigFile = toml::from_str(text)?;
+
+		let sources = [file.flake.is_some(), file.channel.is_some(), file.prebuilt.is_some()];
+		match sources.into_iter().filter(|s| *s).count() {
+			0 => return Err(ConfigError::Invalid("flake", "one of flake, channel or prebuilt must be set".to_string())),
+			1 => (),
+			_ => return Err(ConfigError::Invalid("flake", "only one of flake, channel or prebuilt can be set".to_string())),
+		}
+		let source = if let Some(flake) = &file.flake {
+			Self::flake_source(flake, file.config_name.as_deref(), file.nixos_config.is_some())?
+		} else if let Some(channel) = &file.channel {
+			Self::channel_source(channel, file.nixos_config.clone(), file.config_name.is_some())?
+		} else if let Some(prebuilt) = file.prebuilt {
+			Self::prebuilt_source(prebuilt, file.config_name.as_deref(), file.nixos_config.is_some())?
+		} else {
+			unreachable!()
+		};
+		let source = match source {
+			Source::Flake(mut flake) => {
+				if let Some(inputs) = file.inputs {
+					flake.inputs = Self::check_inputs(inputs)?;
+				}
+				if let Some(lock) = file.lock_file {
+					flake.lock = Self::check_lock_file(lock)?;
+				}
+				Source::Flake(flake)
+			},
+			_ if file.inputs.is_some() =>
+				return Err(ConfigError::Invalid("inputs", "only applies to flakes".to_string())),
+			_ if file.lock_file.is_some() =>
+				return Err(ConfigError::Invalid("lock_file", "only applies to flakes".to_string())),
+			source => source,
+		};
+
+		let profile = file.profile.unwrap_or_else(|| Profile::SYSTEM_PATH.into());
+		if ! profile.is_absolute() {
+			return Err(ConfigError::Invalid("profile",
+				format!("{} is not an absolute path", profile.display())));
+		}
+
+		let target = file.target.unwrap_or(RunTo::Switch);
+		if target == RunTo::Cancel {
+			return Err(ConfigError::Invalid("target", "cancel is not an update target".to_string()));
+		}
+
+		let schedule = file.schedule.map(Self::check_schedule).transpose()?;
+		let trust = file.trust.map(Self::check_trust).transpose()?;
+		let health_check = file.health_check.map(Self::check_health_check).transpose()?;
+		let trial_boot = file.trial_boot.map(Self::check_trial_boot).transpose()?;
+		let gc = file.gc.map(Self::check_gc).transpose()?;
+		let disk_space = file.disk_space.map(|d| SpaceCheck {
+			store_reserve: d.store_reserve_mib.unwrap_or(1024) * 1024 * 1024,
+			boot_reserve: d.boot_reserve_mib.unwrap_or(64) * 1024 * 1024,
+			collect_garbage: d.collect_garbage,
+		});
+
+		Ok(Self {
+			source,
+			profile,
+			target,
+			schedule,
+			trust,
+			health_check,
+			trial_boot,
+			gc,
+			disk_space,
+		})
+	}
+
+	fn flake_source(flake: &str, config_name: Option<&str>, has_nixos_config: bool) -> Result<Source, ConfigError> {
+		if flake.is_empty() {
+			return Err(ConfigError::Invalid("flake", "must not be empty".to_string()));
+		}
+		if has_nixos_config {
+			return Err(ConfigError::Invalid("nixos_config", "only applies to channels".to_string()));
+		}
+		let config_name = match config_name {
+			Some("") =>
+				return Err(ConfigError::Invalid("config_name", "must not be empty".to_string())),
+			Some(n) => n.to_string(),
+			None => Self::hostname()?,
+		};
+		Ok(Source::Flake(FlakeConfig::from_url_and_config_name(flake, &config_name)))
+	}
+
+	fn channel_source(channel: &str, nixos_config: Option<PathBuf>, has_config_name: bool) -> Result<Source, ConfigError> {
+		if channel.is_empty() || channel.contains('/') {
+			return Err(ConfigError::Invalid("channel", "must be a channel name like nixos".to_string()));
+		}
+		if has_config_name {
+			return Err(ConfigError::Invalid("config_name", "only applies to flakes".to_string()));
+		}
+		let nixos_config = nixos_config.unwrap_or_else(|| ChannelConfig::DEFAULT_NIXOS_CONFIG.into());
+		if ! nixos_config.is_absolute() {
+			return Err(ConfigError::Invalid("nixos_config",
+				format!("{} is not an absolute path", nixos_config.display())));
+		}
+		Ok(Source::Channel(ChannelConfig::new(channel, &nixos_config)))
+	}
+
+	fn prebuilt_source(file: PrebuiltFile, config_name: Option<&str>, has_nixos_config: bool) -> Result<Source, ConfigError> {
+		if has_nixos_config {
+			return Err(ConfigError::Invalid("nixos_config", "only applies to channels".to_string()));
+		}
+		if config_name.is_some() && file.cache.is_none() {
+			return Err(ConfigError::Invalid("config_name", "only applies to flakes and prebuilt.cache".to_string()));
+		}
+		let mut substituters = file.substituters;
+		let source = match (file.url, file.file, file.cache) {
+			(Some(url), None, None) => ClosureSource::Url(url),
+			(None, Some(path), None) if path.is_absolute() => ClosureSource::File(path),
+			(None, Some(path), None) =>
+				return Err(ConfigError::Invalid("prebuilt.file", format!("{} is not an absolute path", path.display()))),
+			(None, None, Some(cache)) => {
+				let name = match config_name {
+					Some("") =>
+						return Err(ConfigError::Invalid("config_name", "must not be empty".to_string())),
+					Some(n) => n.to_string(),
+					None => Self::hostname()?,
+				};
+				let url = format!("{}/{}", cache.trim_end_matches('/'), name);
+				if ! substituters.contains(&cache) {
+					substituters.push(cache);
+				}
+				ClosureSource::Url(url)
+			},
+			_ => return Err(ConfigError::Invalid("prebuilt.url", "exactly one of url, file or cache must be set".to_string())),
+		};
+		if substituters.is_empty() {
+			return Err(ConfigError::Invalid("prebuilt.substituters", "at least one binary cache must be set".to_string()));
+		}
+		Ok(Source::Prebuilt(PrebuiltConfig::new(source, &substituters)))
+	}
+
+	fn check_inputs(file: InputsFile) -> Result<InputPolicy, ConfigError> {
+		if let Some(name) = file.pinned.iter().find(|n| file.follow.contains_key(*n)) {
+			return Err(ConfigError::Invalid("inputs.follow", format!("{} is pinned", name)));
+		}
+		if let Some(name) = file.update.iter().flatten().find(|n| file.pinned.contains(n)) {
+			return Err(ConfigError::Invalid("inputs.update", format!("{} is pinned", name)));
+		}
+		Ok(InputPolicy {
+			update: file.update,
+			pinned: file.pinned,
+			follow: file.follow,
+		})
+	}
+
+	fn check_lock_file(file: LockFileFile) -> Result<LockPolicy, ConfigError> {
+		let mode = file.mode.unwrap_or_default();
+		if ! file.commit {
+			if file.author_name.is_some() || file.author_email.is_some() || file.signoff || file.push.is_some() {
+				return Err(ConfigError::Invalid("lock_file.commit",
+					"must be true to use author_name, author_email, signoff or push".to_string()));
+			}
+			return Ok(LockPolicy { mode, commit: None });
+		}
+		let identity = match (file.author_name, file.author_email) {
+			(Some(name), Some(email)) => Some(Identity { name, email }),
+			(None, None) => None,
+			_ => return Err(ConfigError::Invalid("lock_file.author_name",
+				"author_name and author_email must be set together".to_string())),
+		};
+		Ok(LockPolicy {
+			mode,
+			commit: Some(CommitOptions { identity, signoff: file.signoff, push: file.push }),
+		})
+	}
+
+	fn check_schedule(file: ScheduleFile) -> Result<Schedule, ConfigError> {
+		let calendar = file.on_calendar.parse()
+			.map_err(|e| ConfigError::Invalid("schedule.on_calendar", e))?;
+		let target = file.target.unwrap_or(RunTo::Build);
+		if ! matches!(target, RunTo::Check | RunTo::Build | RunTo::SetBoot) {
+			return Err(ConfigError::Invalid("schedule.target",
+				"scheduled updates can only run to check, build or set_boot".to_string()));
+		}
+		Ok(Schedule {
+			calendar,
+			randomized_delay: Duration::from_secs(file.randomized_delay_sec.unwrap_or(0)),
+			target,
+		})
+	}
+
+	fn check_trust(file: TrustFile) -> Result<TrustPolicy, ConfigError> {
+		if file.public_keys.is_empty() {
+			return Err(ConfigError::Invalid("trust.public_keys", "must not be empty".to_string()));
+		}
+		if let Some(key) = file.public_keys.iter().find(|k| ! k.contains(':')) {
+			return Err(ConfigError::Invalid("trust.public_keys", format!("{} is not of the form name:key", key)));
+		}
+		let sigs_needed = file.sigs_needed.unwrap_or(1);
+		if sigs_needed == 0 {
+			return Err(ConfigError::Invalid("trust.sigs_needed", "must be at least 1".to_string()));
+		}
+		Ok(TrustPolicy { public_keys: file.public_keys, sigs_needed })
+	}
+
+	fn check_health_check(file: HealthCheckFile) -> Result<HealthChecks, ConfigError> {
+		if file.command.as_ref().is_some_and(Vec::is_empty) {
+			return Err(ConfigError::Invalid("health_check.command", "must not be empty".to_string()));
+		}
+		if file.units.is_empty() && file.command.is_none() && ! file.failed_units && file.reachable.is_none() {
+			return Err(ConfigError::Invalid("health_check",
+				"needs at least one of units, command, failed_units or reachable".to_string()));
+		}
+		Ok(HealthChecks {
+			units: file.units,
+			command: file.command,
+			failed_units: file.failed_units,
+			reachable: file.reachable,
+			timeout: Duration::from_secs(file.timeout_sec.unwrap_or(120)),
+		})
+	}
+
+	fn check_trial_boot(file: TrialBootFile) -> Result<TrialBoot, ConfigError> {
+		let max_attempts = file.max_attempts.unwrap_or(3);
+		if max_attempts == 0 {
+			return Err(ConfigError::Invalid("trial_boot.max_attempts", "must be at least 1".to_string()));
+		}
+		let deadline = Duration::from_secs(file.deadline_sec.unwrap_or(600));
+		Ok(TrialBoot::new(Path::new(consts::STATE_DIR), deadline, max_attempts))
+	}
+
+	fn check_gc(file: GcFile) -> Result<RetentionPolicy, ConfigError> {
+		if file.keep.is_none() && file.keep_days.is_none() {
+			return Err(ConfigError::Invalid("gc", "at least one of keep or keep_days must be set".to_string()));
+		}
+		Ok(RetentionPolicy {
+			keep: file.keep,
+			keep_days: file.keep_days,
+			pinned: file.pinned,
+			max_freed: file.max_freed,
+		})
+	}
+
+	fn hostname() -> Result<String, ConfigError> {
+		let name = nix::unistd::gethostname().map_err(|e| ConfigError::NoHostname(e.to_string()))?;
+		name.into_string().map_err(|_| ConfigError::NoHostname("hostname is not valid unicode".to_string()))
+	}
+
+	pub fn profile(&self) -> Profile {
+		Profile::new(&self.profile)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn parse_full_config() {
+		let c = Config::parse(r#"
+			flake = "/etc/nixos"
+			config_name = "flink"
+			profile = "/nix/var/nix/profiles/test"
+			target = "set_boot"
+
+			[schedule]
+			on_calendar = "daily 03:00"
+			randomized_delay_sec = 600
+			target = "check"
+
+			[inputs]
+			update = ["nixpkgs", "home-manager"]
+			pinned = ["nixos-hardware"]
+			follow.nixpkgs = "github:NixOS/nixpkgs/nixos-24.05"
+
+			[lock_file]
+			mode = "work_dir"
+			commit = true
+			author_name = "NixOS Updater"
+			author_email = "updater@example.org"
+			signoff = true
+			push = "origin"
+
+			[health_check]
+			units = ["sshd.service", "nginx.service"]
+			command = ["curl", "--fail", "http://localhost"]
+			failed_units = true
+			reachable = "router.lan"
+			timeout_sec = 300
+
+			[trial_boot]
+			deadline_sec = 900
+			max_attempts = 2
+
+			[gc]
+			keep = 5
+			keep_days = 30
+			pinned = [1]
+			max_freed = 10_000_000_000
+
+			[disk_space]
+			store_reserve_mib = 2048
+			collect_garbage = true
+
+			[trust]
+			public_keys = ["cache.example.org-1:MDEyMzQ1Njc4OWFiY2RlZmdoaWprbG1ub3BxcnN0dXY="]
+			sigs_needed = 2
+		"#).unwrap();
+		let mut flake = FlakeConfig::from_url_and_config_name("/etc/nixos", "flink");
+		flake.inputs = InputPolicy {
+			update: Some(vec!["nixpkgs".to_string(), "home-manager".to_string()]),
+			pinned: vec!["nixos-hardware".to_string()],
+			follow: BTreeMap::from([("nixpkgs".to_string(), "github:NixOS/nixpkgs/nixos-24.05".to_string())]),
+		};
+		flake.lock = LockPolicy {
+			mode: LockMode::WorkDir,
+			commit: Some(CommitOptions {
+				identity: Some(Identity { name: "NixOS Updater".to_string(), email: "updater@example.org".to_string() }),
+				signoff: true,
+				push: Some("origin".to_string()),
+			}),
+		};
+		assert_eq!(c.source, Source::Flake(flake));
+		assert_eq!(c.profile, PathBuf::from("/nix/var/nix/profiles/test"));
+		assert_eq!(c.target, RunTo::SetBoot);
+		let s = c.schedule.unwrap();
+		assert_eq!(s.calendar, "03:00".parse().unwrap());
+		assert_eq!(s.randomized_delay, Duration::from_secs(600));
+		assert_eq!(s.target, RunTo::Check);
+		assert_eq!(c.trust, Some(TrustPolicy {
+			public_keys: vec!["cache.example.org-1:MDEyMzQ1Njc4OWFiY2RlZmdoaWprbG1ub3BxcnN0dXY=".to_string()],
+			sigs_needed: 2,
+		}));
+		let h = c.health_check.unwrap();
+		assert_eq!(h.units, vec!["sshd.service", "nginx.service"]);
+		assert_eq!(h.command.unwrap().len(), 3);
+		assert!(h.failed_units);
+		assert_eq!(h.reachable.as_deref(), Some("router.lan"));
+		assert_eq!(h.timeout, Duration::from_secs(300));
+		let t = c.trial_boot.unwrap();
+		assert_eq!(t.deadline, Duration::from_secs(900));
+		assert_eq!(t.max_attempts, 2);
+		assert_eq!(c.gc, Some(RetentionPolicy {
+			keep: Some(5),
+			keep_days: Some(30),
+			pinned: vec![1],
+			max_freed: Some(10_000_000_000),
+		}));
+		assert_eq!(c.disk_space, Some(SpaceCheck {
+			store_reserve: 2048 * 1024 * 1024,
+			boot_reserve: 64 * 1024 * 1024,
+			collect_garbage: true,
+		}));
+	}
+
+	#[test]
+	fn parse_defaults() {
+		let c = Config::parse(r#"flake = "/etc/nixos""#).unwrap();
+		assert_eq!(c.profile, PathBuf::from(Profile::SYSTEM_PATH));
+		assert_eq!(c.target, RunTo::Switch);
+		assert!(c.schedule.is_none());
+		assert!(c.trust.is_none());
+		assert!(c.health_check.is_none());
+		assert!(c.trial_boot.is_none());
+		assert!(c.gc.is_none());
+		assert!(matches!(c.source, Source::Flake(_)));
+
+		let c = Config::parse(r#"channel = "nixos""#).unwrap();
+		assert_eq!(c.source, Source::Channel(ChannelConfig::new("nixos",
+			Path::new(ChannelConfig::DEFAULT_NIXOS_CONFIG))));
+	}
+
+	#[test]
+	fn parse_prebuilt() {
+		let c = Config::parse(r#"
+			config_name = "flink"
+			prebuilt.cache = "https://cache.example.org/"
+			prebuilt.substituters = ["https://cache.nixos.org"]
+		"#).unwrap();
+		assert_eq!(c.source, Source::Prebuilt(PrebuiltConfig::new(
+			ClosureSource::Url("https://cache.example.org/flink".to_string()),
+			&["https://cache.nixos.org".to_string(), "https://cache.example.org/".to_string()])));
+
+		let c = Config::parse(r#"
+			prebuilt.file = "/mnt/ci/flink"
+			prebuilt.substituters = ["https://cache.example.org"]
+		"#).unwrap();
+		assert!(matches!(c.source, Source::Prebuilt(PrebuiltConfig { source: ClosureSource::File(_), .. })));
+	}
+
+	#[test]
+	fn reject_invalid() {
+		assert!(matches!(Config::parse(""), Err(ConfigError::Invalid("flake", _))));
+		assert!(matches!(Config::parse("flake = 1"), Err(ConfigError::ParseError(_))));
+		assert!(matches!(Config::parse(r#"flake = "/etc/nixos"
+			channel = "nixos""#), Err(ConfigError::Invalid("flake", _))));
+		assert!(matches!(Config::parse(r#"channel = "nixos"
+			config_name = "flink""#), Err(ConfigError::Invalid("config_name", _))));
+		assert!(matches!(Config::parse(r#"flake = "/etc/nixos"
+			inputs.pinned = ["nixpkgs"]
+			inputs.follow.nixpkgs = "github:NixOS/nixpkgs/nixos-24.05""#), Err(ConfigError::Invalid("inputs.follow", _))));
+		assert!(matches!(Config::parse(r#"flake = "/etc/nixos"
+			lock_file.push = "origin""#), Err(ConfigError::Invalid("lock_file.commit", _))));
+		assert!(matches!(Config::parse(r#"prebuilt.url = "https://ci.example.org/flink""#),
+			Err(ConfigError::Invalid("prebuilt.substituters", _))));
+		assert!(matches!(Config::parse(r#"prebuilt.substituters = ["https://cache.example.org"]"#),
+			Err(ConfigError::Invalid("prebuilt.url", _))));
+		assert!(matches!(Config::parse(r#"channel = "nixos"
+			prebuilt.url = "https://ci.example.org/flink""#), Err(ConfigError::Invalid("flake", _))));
+		assert!(matches!(Config::parse(r#"flake = "/etc/nixos"
+			trust.public_keys = ["MDEyMzQ1Njc4OWFiY2RlZmdoaWprbG1ub3BxcnN0dXY="]"#), Err(ConfigError::Invalid("trust.public_keys", _))));
+		assert!(matches!(Config::parse(r#"flake = "/etc/nixos"
+			health_check.timeout_sec = 60"#), Err(ConfigError::Invalid("health_check", _))));
+		assert!(matches!(Config::parse(r#"flake = "/etc/nixos"
+			gc.pinned = [1]"#), Err(ConfigError::Invalid("gc", _))));
+		assert!(matches!(Config::parse(r#"flake = "/etc/nixos"
+			target = "cancel""#), Err(ConfigError::Invalid("target", _))));
+		assert!(matches!(Config::parse(r#"flake = "/etc/nixos"
+			profile = "profiles/system""#), Err(ConfigError::Invalid("profile", _))));
+		assert!(matches!(Config::parse(r#"flake = "/etc/nixos"
+			schedule.on_calendar = "daily"
+			schedule.target = "switch""#), Err(ConfigError::Invalid("schedule.target", _))));
+	}
+}
@@ -1,3 +1,4 @@
 
-pub const NAME: &str = "de.afuchs.NixOSUpdater";
+pub use nixos_updater_interface::{NAME, PATH};
+pub const STATE_DIR: &str = "/var/lib/nixos-updater";
 
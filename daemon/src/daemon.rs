@@ -1,54 +1,131 @@
-use std::io;
-use std::io::{BufReader, BufRead};
 use std::path::{Path, PathBuf};
-use std::process::Command;
+use std::sync::Arc;
+use tokio::process::Command;
+use tokio::sync::{mpsc, watch};
+use tokio_util::sync::CancellationToken;
 use crate::errors::*;
 use crate::nix::*;
 use crate::nix::store::*;
 use crate::nix::flake::*;
-use std::sync::mpsc;
-use std::pin::Pin;
-use futures::Future;
-use futures::task::{Context, Poll};
+use crate::nix::channel::ChannelConfig;
+use crate::nix::prebuilt::PrebuiltConfig;
+use crate::nix::lock::InputUpdate;
+use crate::nix::verify::TrustPolicy;
+use crate::nix::gc::{self, RetentionPolicy};
+use crate::nix::plan::{DryRun, SpaceCheck};
+use crate::nix::command::{CommandContext, piped_command, run_command};
+use crate::nix::progress::Progress;
+use crate::config::{Config, Source};
+use crate::health::HealthChecks;
+use crate::trial::TrialBoot;
+use crate::runlog::RunLog;
+use crate::consts;
+use enum_variants_strings::EnumVariantsStrings;
 use tokio::task::JoinHandle;
 
 trait Manageable: Updateable + Buildable {}
 impl<T: Updateable + Buildable> Manageable for T {}
 
+/// A part of the system that can only be replaced by rebooting.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RebootReason {
+	pub component: &'static str,
+	/// versions before and after the upgrade, if the component has one
+	pub versions: Option<(String, String)>,
+}
+
+impl std::fmt::Display for RebootReason {
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		match &self.versions {
+			Some((old, new)) if old != new => write!(f, "{} {} → {}", self.component, old, new),
+			_ => write!(f, "{} changed", self.component),
+		}
+	}
+}
+
 #[derive(Debug)]
 pub enum UpgradeNeeds {
 	None,
 	Switch,
-	Reboot,
+	Reboot(Vec<RebootReason>),
 }
 
 impl UpgradeNeeds {
-	fn read_system_file_link(base: &StorePath, suffix: &str) -> Result<StorePath, StorePathError> {
-		let mut pb: PathBuf = base.into();
-		pb.push(suffix);
-		todo!();
+	/// links in a system's toplevel that are only picked up at boot, with their display names
+	const BOOT_LINKS: [(&'static str, &'static str); 5] = [
+		("kernel", "kernel"),
+		("initrd", "initrd"),
+		("systemd", "systemd"),
+		("firmware", "firmware"),
+		("append-initrd-secrets", "initrd secrets"),
+	];
+
+	/// the state announcing this need, None if there is nothing to do
+	fn requires(&self) -> Option<UpgradeState> {
+		match self {
+			UpgradeNeeds::None => None,
+			UpgradeNeeds::Switch => Some(UpgradeState::RequiresSwitch),
+			UpgradeNeeds::Reboot(reasons) => Some(UpgradeState::RequiresReboot(reasons.clone())),
+		}
+	}
+
+	/// Target of the link `suffix` in the system `base`, None if the system has no such link.
+	fn read_system_file_link(base: &StorePath, suffix: &str) -> Result<Option<StorePath>, StorePathError> {
+		let link = base.subpath(suffix);
+		match link.symlink_metadata() {
+			Ok(_) => Ok(Some(StorePath::new(&link)?)),
+			Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(None),
+			Err(e) => Err(e.into()),
+		}
 	}
 
-	fn sublink_eq(from: &StorePath, to: &StorePath, sub: &str) -> Result<bool, StorePathError> {
-		let p1 = StorePath::new(&from.subpath(sub))?;
-		let p2 = StorePath::new(&to.subpath(sub))?;
-		Ok(p1 == p2)
+	fn compare_link(from: &StorePath, to: &StorePath, sub: &str, component: &'static str)
+			-> Result<Option<RebootReason>, StorePathError> {
+		let old = Self::read_system_file_link(from, sub)?;
+		let new = Self::read_system_file_link(to, sub)?;
+		if old == new {
+			return Ok(None);
+		}
+		let versions = old.as_ref().and_then(StorePath::version)
+			.zip(new.as_ref().and_then(StorePath::version))
+			.map(|(o, n)| (o.to_string(), n.to_string()));
+		Ok(Some(RebootReason { component, versions }))
+	}
+
+	fn read_kernel_params(system: &StorePath) -> Result<String, StorePathError> {
+		match std::fs::read_to_string(system.subpath("kernel-params")) {
+			Ok(p) => Ok(p.trim().to_string()),
+			Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(String::new()),
+			Err(e) => Err(e.into()),
+		}
 	}
 
 	pub fn compare(from: &StorePath, to: &StorePath) -> Result<Self, StorePathError> {
 		if from == to {
 			return Ok(UpgradeNeeds::None);
 		}
-		if Self::sublink_eq(from, to, "initrd")?
-				&& Self::sublink_eq(from, to, "kernel")?
-				&& Self::sublink_eq(from, to, "kernel-modules")? {
-			return Ok(UpgradeNeeds::Switch);
+		let mut reasons = Vec::new();
+		for (sub, component) in Self::BOOT_LINKS {
+			reasons.extend(Self::compare_link(from, to, sub, component)?);
+		}
+		// modules are built for the kernel, only mention them on their own if the kernel stayed
+		if ! reasons.iter().any(|r| r.component == "kernel") {
+			reasons.extend(Self::compare_link(from, to, "kernel-modules", "kernel modules")?);
+		}
+		if Self::read_kernel_params(from)? != Self::read_kernel_params(to)? {
+			reasons.push(RebootReason { component: "kernel parameters", versions: None });
+		}
+
+		if reasons.is_empty() {
+			Ok(UpgradeNeeds::Switch)
+		} else {
+			Ok(UpgradeNeeds::Reboot(reasons))
 		}
-		Ok(UpgradeNeeds::Reboot)
 	}
 }
 
-#[derive(Debug)]
+#[derive(Debug, Clone, Copy, PartialEq, serde::Serialize, serde::Deserialize, EnumVariantsStrings)]
+#[serde(rename_all = "snake_case")]
 pub enum RunTo {
 	Cancel,
 	Check,
@@ -58,118 +135,507 @@ pub enum RunTo {
 	Reboot,
 }
 
-#[derive(Debug, PartialEq)]
+impl RunTo {
+	/// Command to answer `needs` with for a process running to this target.
+	///
+	/// Processes running to `Check` or `Build` do not ask, an update that requires
+	/// a reboot can not be switched to and is made the boot default instead.
+	pub fn command_for(self, needs: &UpgradeState) -> Option<RunTo> {
+		if ! matches!(needs, UpgradeState::RequiresSwitch | UpgradeState::RequiresReboot(_)) {
+			return None;
+		}
+		match (self, needs) {
+			(RunTo::Check | RunTo::Build, _) => None,
+			(RunTo::Switch, UpgradeState::RequiresReboot(_)) => Some(RunTo::SetBoot),
+			(t, _) => Some(t),
+		}
+	}
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub enum UpgradeState {
 	UpdatingInputs,
+	/// inputs whose revision the update changed or held back
+	InputsUpdated(InputUpdate),
 	CheckingUpgrades,
+	/// system the update leads to, sent as soon as it is known
+	NewSystem(StorePath),
 	BuildingOutput,
+	UpgradeAvailable,
 	RequiresSwitch,
 	SwitchingBoot,
-	RequiresReboot,
+	/// new system can only be activated by rebooting, for the given reasons
+	RequiresReboot(Vec<RebootReason>),
+	VerifyingSignatures,
 	SwitchingConfiguration,
+	CheckingHealth,
+	/// health checks failed, switching back to the previous system
+	RollingBack,
+	RolledBack,
 	Rebooting,
 	Done
 }
 
 pub struct UpgradeProcessInfo {
-	out_queue: Option<mpsc::Receiver<UpgradeState>>,
-	in_queue: mpsc::Sender<RunTo>,
-	result: Option<JoinHandle<Result<(), UpgradeError>>>,
+	pub out_queue: Option<mpsc::UnboundedReceiver<UpgradeState>>,
+	pub in_queue: mpsc::UnboundedSender<RunTo>,
+	/// stops the process as soon as possible, except while switching configurations
+	pub cancel: CancellationToken,
+	pub progress: watch::Receiver<Progress>,
+	pub result: Option<JoinHandle<Result<(), UpgradeError>>>,
 }
 
 pub struct UpgradeProcess {
-	input: Box<dyn Manageable + Send>,
+	input: Box<dyn Manageable + Send + Sync>,
 	profile: Profile,
+	/// link keeping a built but not yet applied system alive
+	gc_root: PathBuf,
+	/// signatures required before switching, None to switch to any closure
+	trust: Option<TrustPolicy>,
+	/// checks after switching, None to keep any system that switched successfully
+	health: Option<HealthChecks>,
+	/// confirmation of systems after rebooting into them, None to keep any system that boots
+	trial: Option<TrialBoot>,
+	/// generations deleted after switching, None to keep all
+	gc: Option<RetentionPolicy>,
+	/// free space required before building, None to not check
+	space: Option<SpaceCheck>,
+	ctx: CommandContext,
 }
 
 impl UpgradeProcess {
-	pub fn for_flake(flake: FlakeConfig) -> Self {
+	fn for_input(input: Box<dyn Manageable + Send + Sync>) -> Self {
 		Self {
-			input: Box::new(flake),
+			input,
 			profile: Profile::system(),
+			gc_root: Path::new(consts::STATE_DIR).join("result"),
+			trust: None,
+			health: None,
+			trial: None,
+			gc: None,
+			space: None,
+			ctx: CommandContext::new(),
 		}
 	}
 
+	pub fn for_flake(flake: FlakeConfig) -> Self {
+		Self::for_input(Box::new(flake))
+	}
+
+	pub fn for_channel(channel: ChannelConfig) -> Self {
+		Self::for_input(Box::new(channel))
+	}
+
+	pub fn for_prebuilt(prebuilt: PrebuiltConfig) -> Self {
+		Self::for_input(Box::new(prebuilt))
+	}
+
+	pub fn for_config(config: &Config) -> Self {
+		let process = match &config.source {
+			Source::Flake(flake) => Self::for_flake(flake.clone()),
+			Source::Channel(channel) => Self::for_channel(channel.clone()),
+			Source::Prebuilt(prebuilt) => Self::for_prebuilt(prebuilt.clone()),
+		};
+		Self {
+			profile: config.profile(),
+			trust: config.trust.clone(),
+			health: config.health_check.clone(),
+			trial: config.trial_boot.clone(),
+			gc: config.gc.clone(),
+			space: config.disk_space.clone(),
+			..process
+		}
+	}
+
+	/// Writes the output of the commands the process runs to `log`.
+	pub fn with_log(mut self, log: Arc<RunLog>) -> Self {
+		self.ctx.set_log(log);
+		self
+	}
+
 	fn compute_required_action(&self, new: &BuildOutput) -> Result<UpgradeNeeds, UpgradeError> {
 		let sys = self.profile.get_current()?;
 		Ok(UpgradeNeeds::compare(&sys, &new.path)?)
 	}
 
-	fn exec_switch_to_configuration(&self, out: &BuildOutput, arg: &str) -> Result<(), UpgradeError> {
-		let binary = out.path.subpath("bin/switch-to-configuration");
-		let mut chld = Command::new(binary).arg(arg).spawn().map_err(UpgradeError::map_switch_io_error)?;
-		let out = chld.wait().map_err(UpgradeError::map_switch_io_error)?;
-		if ! out.success() {
-			return Err(UpgradeError::SwitchFailed(None));
-		}
+	/// Not cancellable, interrupting switch-to-configuration could leave the system half switched.
+	async fn exec_switch_to_configuration(&self, system: &StorePath, arg: &str) -> Result<(), UpgradeError> {
+		let binary = system.subpath("bin/switch-to-configuration");
+		let mut cmd = piped_command(&binary.to_string_lossy());
+		cmd.arg(arg);
+		self.ctx.log(&format!("$ {} {}", binary.display(), arg));
+		run_command(cmd, &CancellationToken::new(), |line| {
+			log::info!("{}", line);
+			self.ctx.log(line);
+		}).await.map_err(UpgradeError::SwitchFailed)?;
 		Ok(())
 	}
 
-	fn switch_to(&self, out: &BuildOutput) -> Result<(), UpgradeError> {
-		self.exec_switch_to_configuration(out, "switch")
+	/// Adds `system` as a new generation of the profile, see [`Profile::set`].
+	/// Not cancellable for the same reason as [`Self::exec_switch_to_configuration`].
+	async fn add_generation(&self, system: &StorePath) -> Result<(), UpgradeError> {
+		self.profile.set(system, &CancellationToken::new()).await
+			.map_err(UpgradeError::map_profile_error)
 	}
 
-	fn make_boot_default(&self, out: &BuildOutput) -> Result<(), UpgradeError> {
-		self.exec_switch_to_configuration(out, "boot")
+	/// Switches to `out` and, if health checks are configured, back to the current system if they fail.
+	async fn switch_to(&self, out: &BuildOutput, out_tx: &mpsc::UnboundedSender<UpgradeState>) -> Result<(), UpgradeError> {
+		let previous = self.profile.get_current()?;
+		let previous_generation = self.profile.generations()?.into_iter().find(|g| g.current);
+		let baseline = match &self.health {
+			Some(health) => health.baseline().await,
+			None => None,
+		};
+		out_tx.send(UpgradeState::SwitchingConfiguration).unwrap();
+		self.add_generation(&out.path).await?;
+		self.exec_switch_to_configuration(&out.path, "switch").await?;
+		let Some(health) = &self.health else {
+			return Ok(());
+		};
+
+		out_tx.send(UpgradeState::CheckingHealth).unwrap();
+		let Err(e) = health.wait_healthy(baseline).await else {
+			return Ok(());
+		};
+		log::error!("health check failed, switching back to {}: {}", previous, e);
+		out_tx.send(UpgradeState::RollingBack).unwrap();
+		match previous_generation {
+			Some(g) => self.profile.switch_generation(g.number, &CancellationToken::new()).await
+				.map_err(UpgradeError::map_profile_error)?,
+			None => self.add_generation(&previous).await?,
+		}
+		self.exec_switch_to_configuration(&previous, "switch").await?;
+		out_tx.send(UpgradeState::RolledBack).unwrap();
+		Err(UpgradeError::RolledBack(e))
 	}
 
-	fn reboot(&self) -> Result<(), UpgradeError> {
+	async fn make_boot_default(&self, out: &BuildOutput) -> Result<(), UpgradeError> {
+		self.add_generation(&out.path).await?;
+		self.exec_switch_to_configuration(&out.path, "boot").await
+	}
+
+	async fn reboot(&self) -> Result<(), UpgradeError> {
 		let out = Command::new("reboot")
-			.output().map_err(UpgradeError::map_reboot_failed)?;
+			.output().await.map_err(UpgradeError::map_reboot_failed)?;
 		if ! out.status.success() {
 			Err(UpgradeError::RebootFailed(String::from_utf8(out.stderr).unwrap()))?;
 		}
 		Ok(())
 	}
 
+	/// Refuses closures not signed as required by the trust policy.
+	async fn verify(&self, out: &BuildOutput, out_tx: &mpsc::UnboundedSender<UpgradeState>) -> Result<(), UpgradeError> {
+		let Some(trust) = &self.trust else {
+			return Ok(());
+		};
+		out_tx.send(UpgradeState::VerifyingSignatures).unwrap();
+		trust.verify(&out.path, &self.ctx).await?;
+		Ok(())
+	}
+
+	/// Makes `out` the boot default, on trial if trial boots are configured.
+	async fn set_boot(&self, out: &BuildOutput) -> Result<(), UpgradeError> {
+		let previous = self.profile.get_current()?;
+		self.make_boot_default(out).await?;
+		if let Some(trial) = &self.trial {
+			if let Err(e) = trial.record(&out.path, &previous) {
+				log::warn!("could not record trial boot, {} will not be confirmed: {}", out.path, e);
+			}
+		}
+		Ok(())
+	}
+
+	/// Confirms a system booted on trial with the health checks, or makes the
+	/// previous system the boot default again and reboots into it.
+	pub async fn confirm_boot(&self) -> Result<(), UpgradeError> {
+		let Some(trial) = &self.trial else {
+			return Ok(());
+		};
+		let booted = StorePath::new(Path::new(Profile::BOOTED_SYSTEM))?;
+		let pending = match trial.start_attempt(&booted) {
+			Ok(Some(p)) => p,
+			Ok(None) => return Ok(()),
+			Err(e) => {
+				log::warn!("could not read pending trial boot: {}", e);
+				return Ok(());
+			},
+		};
+
+		let confirmed = if pending.attempts > trial.max_attempts {
+			Err(ConfirmError::AttemptsExhausted(trial.max_attempts))
+		} else if let Some(health) = &self.health {
+			// there is no failed unit count from before the boot to compare with
+			match tokio::time::timeout(trial.deadline, health.wait_healthy(None)).await {
+				Ok(r) => r.map_err(ConfirmError::from),
+				Err(_) => Err(ConfirmError::DeadlineExceeded(trial.deadline.as_secs())),
+			}
+		} else {
+			Ok(())
+		};
+		let Err(e) = confirmed else {
+			log::info!("confirmed trial boot of {}", booted);
+			if let Err(e) = trial.clear() {
+				log::warn!("could not clear pending trial boot: {}", e);
+			}
+			return Ok(());
+		};
+
+		log::error!("trial boot of {} failed, falling back to {}: {}", booted, pending.previous, e);
+		self.add_generation(&pending.previous).await?;
+		self.exec_switch_to_configuration(&pending.previous, "boot").await?;
+		if let Err(e) = trial.clear() {
+			log::warn!("could not clear pending trial boot: {}", e);
+		}
+		self.reboot().await?;
+		Err(UpgradeError::FellBack(e))
+	}
+
+	/// Lets the input record that its update was applied, `kept` if it was resumed. The system is
+	/// already switched at this point, so failures are only logged.
+	async fn accept(&self, kept: bool) {
+		if let Err(e) = self.input.accept(&self.ctx, kept).await {
+			log::warn!("could not record applied update: {}", e);
+		}
+	}
+
+	/// Deletes the generations the retention policy does not keep. Like [`Self::accept`],
+	/// this runs once the new system is switched to or made the boot default, so failures are only logged.
+	async fn collect_garbage(&self) {
+		let Some(gc) = &self.gc else {
+			return;
+		};
+		match gc.collect(&self.profile, &self.ctx.cancel).await {
+			Ok(deleted) if ! deleted.is_empty() => log::info!("deleted generations {:?}", deleted),
+			Ok(_) => (),
+			Err(e) => log::warn!("could not collect garbage: {}", e),
+		}
+	}
+
+	/// Fails if realising `dry_run` would fill the store or /boot, collecting garbage first if allowed.
+	async fn check_space(&self, dry_run: &DryRun) -> Result<(), UpgradeError> {
+		let Some(space) = &self.space else {
+			return Ok(());
+		};
+		let current = self.profile.get_current().ok();
+		let check = || space.check(&dry_run.plan, &dry_run.path, current.as_ref());
+		let e = match check() {
+			Err(e) if space.collect_garbage => e,
+			r => return Ok(r?),
+		};
+		log::info!("{}, collecting garbage", e);
+		let freed = match &self.gc {
+			Some(gc) => gc.collect(&self.profile, &self.ctx.cancel).await.map(|_| ()),
+			None => gc::collect_store(Some(e.needed - e.available), &self.ctx.cancel).await,
+		};
+		if let Err(gc_error) = freed {
+			log::warn!("could not collect garbage: {}", gc_error);
+			return Err(e.into());
+		}
+		Ok(check()?)
+	}
+
+	/// Generations [`Self::collect_garbage`] would delete, empty if there is no retention policy.
+	pub fn garbage_preview(&self) -> Result<Vec<u32>, GcError> {
+		match &self.gc {
+			Some(gc) => gc.preview(&self.profile),
+			None => Ok(Vec::new()),
+		}
+	}
+
+	/// Keeps `out` alive as a GC root so that a later process can resume from it.
+	async fn keep_result(&self, out: &BuildOutput) -> Result<(), UpgradeError> {
+		if let Some(dir) = self.gc_root.parent() {
+			std::fs::create_dir_all(dir).map_err(BuildError::from)?;
+		}
+		out.add_root(&self.gc_root, &self.ctx.cancel).await?;
+		Ok(self.input.keep()?)
+	}
+
+	/// Compares the current system with the build kept for a later [`Self::resume`].
+	pub async fn change_summary(&self) -> Result<diff::ClosureDiff, DiffError> {
+		if ! self.gc_root.exists() {
+			return Err(DiffError::NoPendingBuild);
+		}
+		let new = StorePath::new(&self.gc_root)?;
+		diff::diff_closures(&self.profile.get_current()?, &new, &self.ctx).await
+	}
+
+	/// Removes the build kept by an earlier `run` to `Build` once it is outdated by an applied one.
+	fn drop_kept_result(&self) {
+		match std::fs::remove_file(&self.gc_root) {
+			Err(e) if e.kind() != std::io::ErrorKind::NotFound =>
+				log::warn!("could not remove GC root {}: {}", self.gc_root.display(), e),
+			_ => (),
+		}
+	}
+
+	fn kept_result(&self) -> Result<BuildOutput, UpgradeError> {
+		if ! self.gc_root.exists() {
+			return Err(UpgradeError::NothingToResume);
+		}
+		Ok(BuildOutput::from_root(&self.gc_root)?)
+	}
+
+	/// Tells the caller what `out` requires and carries out the command it answers with.
+	///
+	/// `out` is kept as GC root if the command is `Check` or `Build` and it is not `kept` already,
+	/// a kept build is removed once `out` is applied or turns out to be the current system.
+	async fn apply(&self, out: BuildOutput, kept: bool, out_tx: &mpsc::UnboundedSender<UpgradeState>,
+			in_rx: &mut mpsc::UnboundedReceiver<RunTo>) -> Result<(), UpgradeError> {
+		let Some(state) = self.compute_required_action(&out)?.requires() else {
+			self.drop_kept_result();
+			out_tx.send(UpgradeState::Done).unwrap();
+			return Ok(());
+		};
+		out_tx.send(state).unwrap();
+
+		let cmd = tokio::select! {
+			cmd = in_rx.recv() => cmd.unwrap_or(RunTo::Cancel),
+			_ = self.ctx.cancel.cancelled() => RunTo::Cancel,
+		};
+		match cmd {
+			RunTo::Cancel => {
+				out_tx.send(UpgradeState::Done).unwrap();
+				return Err(UpgradeError::Cancelled);
+			},
+			RunTo::Check | RunTo::Build if kept => (),
+			RunTo::Check | RunTo::Build => {
+				self.keep_result(&out).await?;
+			},
+			RunTo::Switch => {
+				self.verify(&out, out_tx).await?;
+				self.switch_to(&out, out_tx).await?;
+				self.drop_kept_result();
+				self.accept(kept).await;
+				self.collect_garbage().await;
+			},
+			RunTo::SetBoot => {
+				self.verify(&out, out_tx).await?;
+				out_tx.send(UpgradeState::SwitchingBoot).unwrap();
+				self.set_boot(&out).await?;
+				self.drop_kept_result();
+				self.accept(kept).await;
+				self.collect_garbage().await;
+			},
+			RunTo::Reboot => {
+				self.verify(&out, out_tx).await?;
+				out_tx.send(UpgradeState::SwitchingBoot).unwrap();
+				self.set_boot(&out).await?;
+				self.drop_kept_result();
+				self.accept(kept).await;
+				self.collect_garbage().await;
+				out_tx.send(UpgradeState::Rebooting).unwrap();
+				self.reboot().await?;
+			},
+		}
+		out_tx.send(UpgradeState::Done).unwrap();
+		Ok(())
+	}
+
+	/// Updates, evaluates and builds the configuration, stopping early if `target` is `Check` or `Build`.
+	///
+	/// A build that is not switched to immediately is kept as a GC root, see [`Self::resume`].
 	pub fn run(self, target: RunTo) -> UpgradeProcessInfo {
-		let (out_tx, out_queue) = mpsc::channel();
-		let (in_queue, in_rx) = mpsc::channel();
+		let (out_tx, out_queue) = mpsc::unbounded_channel();
+		let (in_queue, mut in_rx) = mpsc::unbounded_channel();
+		let cancel = self.ctx.cancel.clone();
+		let progress = self.ctx.subscribe_progress();
 
+		log::debug!("starting upgrade process, running to {:?}", target);
 		let result = tokio::spawn(async move {
 			out_tx.send(UpgradeState::UpdatingInputs).unwrap();
-			self.input.update()?;
+			let changes = self.input.update(&self.ctx).await?;
+			out_tx.send(UpgradeState::InputsUpdated(changes)).unwrap();
 			out_tx.send(UpgradeState::CheckingUpgrades).unwrap();
-			let out = self.input.dry_build()?;
+			let dry_run = self.input.dry_build(&self.ctx).await?;
+			let new = dry_run.path.clone();
+			out_tx.send(UpgradeState::NewSystem(new.clone())).unwrap();
+			log::debug!("new system would be {}, {:?}", new, dry_run.plan);
+			if target == RunTo::Check {
+				if new != self.profile.get_current()? {
+					out_tx.send(UpgradeState::UpgradeAvailable).unwrap();
+				}
+				out_tx.send(UpgradeState::Done).unwrap();
+				return Ok(());
+			}
+
+			self.check_space(&dry_run).await?;
 			out_tx.send(UpgradeState::BuildingOutput).unwrap();
-			let out = self.input.build()?;
-			let action = self.compute_required_action(&out)?;
-			match action {
-				UpgradeNeeds::None => {
-					out_tx.send(UpgradeState::Done).unwrap();
-					return Ok::<(), UpgradeError>(());
-				},
-				UpgradeNeeds::Switch => {
-					out_tx.send(UpgradeState::RequiresSwitch);
-				},
-				UpgradeNeeds::Reboot => {
-					out_tx.send(UpgradeState::RequiresReboot);
-				},
+			let out = self.input.build(&self.ctx).await?;
+			if target == RunTo::Build {
+				let action = self.compute_required_action(&out)?;
+				match action.requires() {
+					Some(state) => {
+						self.keep_result(&out).await?;
+						out_tx.send(state).unwrap();
+					},
+					None => self.drop_kept_result(),
+				}
+				out_tx.send(UpgradeState::Done).unwrap();
+				return Ok(());
 			}
 
-			let cmd = in_rx.recv().unwrap();
-			match cmd {
-				RunTo::Cancel => {
-					out_tx.send(UpgradeState::Done).unwrap();
-					return Err(UpgradeError::Cancelled);
-				},
-				RunTo::Check | RunTo::Build => {
-					todo!();
-				},
-				RunTo::Switch => {
-					out_tx.send(UpgradeState::SwitchingConfiguration);
-					self.switch_to(&out)?;
-				},
-				RunTo::SetBoot => {
-					out_tx.send(UpgradeState::SwitchingBoot);
-					self.make_boot_default(&out)?;
-				},
-				RunTo::Reboot => {
-					out_tx.send(UpgradeState::SwitchingBoot);
-					self.make_boot_default(&out)?;
-					out_tx.send(UpgradeState::Rebooting);
-					self.reboot()?;
-				},
+			self.apply(out, false, &out_tx, &mut in_rx).await
+		});
+
+		UpgradeProcessInfo {
+			out_queue: Some(out_queue),
+			in_queue,
+			cancel,
+			progress,
+			result: Some(result),
+		}
+	}
+
+	/// Continues with the result kept by an earlier `run` to `Build` instead of building again.
+	/// The result stays kept unless `target` applies it.
+	pub fn resume(self, target: RunTo) -> UpgradeProcessInfo {
+		let (out_tx, out_queue) = mpsc::unbounded_channel();
+		let (in_queue, mut in_rx) = mpsc::unbounded_channel();
+		let cancel = self.ctx.cancel.clone();
+		let progress = self.ctx.subscribe_progress();
+
+		log::debug!("resuming upgrade process from {}, running to {:?}", self.gc_root.display(), target);
+		let result = tokio::spawn(async move {
+			let out = self.kept_result()?;
+			out_tx.send(UpgradeState::NewSystem(out.path.clone())).unwrap();
+			self.apply(out, true, &out_tx, &mut in_rx).await
+		});
+
+		UpgradeProcessInfo {
+			out_queue: Some(out_queue),
+			in_queue,
+			cancel,
+			progress,
+			result: Some(result),
+		}
+	}
+
+	/// Points the profile back to generation `number` and activates it as `target`,
+	/// which is `Switch`, `SetBoot` or `Reboot`.
+	pub fn rollback(self, number: u32, target: RunTo) -> UpgradeProcessInfo {
+		let (out_tx, out_queue) = mpsc::unbounded_channel();
+		let (in_queue, _) = mpsc::unbounded_channel();
+		let cancel = self.ctx.cancel.clone();
+		let progress = self.ctx.subscribe_progress();
+
+		log::debug!("rolling back to generation {}, running to {:?}", number, target);
+		let result = tokio::spawn(async move {
+			let generation = self.profile.generation(number)?
+				.ok_or(UpgradeError::NoSuchGeneration(number))?;
+			out_tx.send(UpgradeState::NewSystem(generation.path.clone())).unwrap();
+			self.profile.switch_generation(number, &self.ctx.cancel).await
+				.map_err(UpgradeError::map_profile_error)?;
+			if target == RunTo::Switch {
+				out_tx.send(UpgradeState::SwitchingConfiguration).unwrap();
+				self.exec_switch_to_configuration(&generation.path, "switch").await?;
+			} else {
+				out_tx.send(UpgradeState::SwitchingBoot).unwrap();
+				self.exec_switch_to_configuration(&generation.path, "boot").await?;
+				if target == RunTo::Reboot {
+					out_tx.send(UpgradeState::Rebooting).unwrap();
+					self.reboot().await?;
+				}
 			}
 			out_tx.send(UpgradeState::Done).unwrap();
 			Ok(())
@@ -178,21 +644,25 @@ impl UpgradeProcess {
 		UpgradeProcessInfo {
 			out_queue: Some(out_queue),
 			in_queue,
+			cancel,
+			progress,
 			result: Some(result),
 		}
 	}
 }
 
-pub async fn debug_main() -> anyhow::Result<()> {
-	let d = UpgradeProcess::for_flake(FlakeConfig::from_url_and_config_name("/home/alex/.config/nixpkgs", "flink"));
+pub async fn debug_main(config_path: &Path) -> anyhow::Result<()> {
+	let config = Config::load(config_path)?;
+	let d = UpgradeProcess::for_config(&config);
 
-	let mut r = d.run(RunTo::Build);
+	let mut r = d.run(config.target);
 
-	for i in r.out_queue.take().unwrap() {
+	let mut states = r.out_queue.take().unwrap();
+	while let Some(i) = states.recv().await {
 		println!("got {:?}", i);
-		if i == UpgradeState::RequiresReboot {
-			println!("sending reboot command");
-			r.in_queue.send(RunTo::Reboot).unwrap();
+		if let Some(cmd) = config.target.command_for(&i) {
+			println!("sending {:?} command", cmd);
+			r.in_queue.send(cmd).unwrap();
 		}
 	}
 
@@ -201,3 +671,32 @@ pub async fn debug_main() -> anyhow::Result<()> {
 	Ok(())
 }
 
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn commands_for_targets() {
+		use UpgradeState::*;
+		assert_eq!(RunTo::Build.command_for(&RequiresSwitch), None);
+		assert_eq!(RunTo::Switch.command_for(&BuildingOutput), None);
+		assert_eq!(RunTo::Switch.command_for(&RequiresSwitch), Some(RunTo::Switch));
+		assert_eq!(RunTo::Switch.command_for(&RequiresReboot(vec![])), Some(RunTo::SetBoot));
+		assert_eq!(RunTo::Reboot.command_for(&RequiresReboot(vec![])), Some(RunTo::Reboot));
+	}
+
+	#[test]
+	fn describe_reboot_reasons() {
+		let kernel = RebootReason {
+			component: "kernel",
+			versions: Some(("6.6.20".to_string(), "6.6.22".to_string())),
+		};
+		assert_eq!(kernel.to_string(), "kernel 6.6.20 → 6.6.22");
+		let systemd = RebootReason {
+			component: "systemd",
+			versions: Some(("255.4".to_string(), "255.4".to_string())),
+		};
+		assert_eq!(systemd.to_string(), "systemd changed");
+	}
+}
@@ -1,39 +1,74 @@
 use dbus::{Path, Message};
-use dbus::arg::RefArg;
+use dbus::arg::{PropMap, RefArg};
+use dbus::channel::Sender;
 use dbus::message::MatchRule;
 use dbus::channel::MatchingReceiver;
+use dbus::nonblock::{Proxy, SyncConnection};
 use futures::future;
 use dbus_tokio::connection;
-use dbus_crossroads::{Crossroads, Context, PropContext, MethodErr, IfaceBuilder};
-use tokio::time::sleep;
-use std::time::Duration;
+use dbus_crossroads::{Context, Crossroads, PropContext, MethodErr, IfaceBuilder, IfaceToken};
 use std::sync::{Mutex, Arc};
-use std::fmt;
-use log::warn;
+use std::path::{Path as FsPath, PathBuf};
+use std::time::Duration;
+use chrono::{DateTime, Local};
+use tokio::sync::{watch, Notify};
+use tokio_util::sync::CancellationToken;
+use log::{warn, error, info};
 
 use crate::consts;
+use enum_variants_strings::EnumVariantsStrings;
+use crate::daemon::{UpgradeProcess, UpgradeProcessInfo, UpgradeState, RunTo, RebootReason};
+use crate::config::Config;
+use crate::errors::{AuthError, UpgradeError, ConfigError, DiffError};
+use crate::polkit::{check_authorization, Action};
+use crate::scheduler::Scheduler;
+use crate::nix::progress::Progress;
+use crate::nix::diff::ClosureDiff;
+use crate::nix::lock::{InputChange, InputUpdate, LockedInput};
+use crate::nix::{Generation, derivation_log};
+use crate::nix::store::StorePath;
+use crate::runlog::{RunLog, RunLogs, RunInfo};
+use crate::history::{History, RunRecord, Trigger};
+use nixos_updater_interface::{self as interface, error, ErrorDetails};
 
 #[derive(Debug)]
 enum ProcessState {
+	Updating,
+	Checking,
 	Building,
+	Verifying,
 	Switching,
+	CheckingHealth,
+	RollingBack,
+	SettingBoot,
+	Rebooting,
 }
 
 impl ProcessState {
 	fn to_str(&self) -> &'static str {
 		use ProcessState::*;
 		match self {
+			Updating => "updating",
+			Checking => "checking",
 			Building => "building",
+			Verifying => "verifying",
 			Switching => "switching",
+			CheckingHealth => "checking_health",
+			RollingBack => "rolling_back",
+			SettingBoot => "setting_boot",
+			Rebooting => "rebooting",
 		}
 	}
 }
 
 #[derive(Debug)]
+#[allow(clippy::enum_variant_names)]
 enum UpdateError {
 	EvaluationFailed,
 	BuildFailed,
+	VerificationFailed,
 	SwitchFailed,
+	RolledBack,
 }
 
 impl UpdateError {
@@ -42,7 +77,29 @@ impl UpdateError {
 		match self {
 			EvaluationFailed => "evaluation_failed",
 			BuildFailed => "build_failed",
+			VerificationFailed => "verification_failed",
 			SwitchFailed => "switch_failed",
+			RolledBack => "rolled_back",
+		}
+	}
+
+	/// `stage` is the last state the upgrade process reported before failing,
+	/// it is used to tell evaluation errors from build errors.
+	fn from_upgrade_error(e: &UpgradeError, stage: Option<&UpgradeState>) -> Self {
+		use UpdateError::*;
+		match e {
+			UpgradeError::UpdateError(_) => EvaluationFailed,
+			UpgradeError::BuildError(_) | UpgradeError::StorePathError(_) => match stage {
+				Some(UpgradeState::BuildingOutput) => BuildFailed,
+				_ => EvaluationFailed,
+			},
+			UpgradeError::NothingToResume | UpgradeError::NotEnoughSpace(_) => BuildFailed,
+			UpgradeError::NoSuchGeneration(_) => SwitchFailed,
+			UpgradeError::VerificationFailed(_) => VerificationFailed,
+			UpgradeError::RolledBack(_) | UpgradeError::FellBack(_) => RolledBack,
+			UpgradeError::SwitchFailed(_)
+				| UpgradeError::RebootFailed(_)
+				| UpgradeError::Cancelled => SwitchFailed,
 		}
 	}
 }
@@ -50,12 +107,28 @@ impl UpdateError {
 #[derive(Debug)]
 struct UpgradeReadyInfo {
 	requires_reboot: bool,
+	/// why the update can only be activated by rebooting, see [`crate::daemon::RebootReason`]
+	reboot_reasons: Vec<String>,
+}
+
+impl UpgradeReadyInfo {
+	fn switch() -> Self {
+		Self { requires_reboot: false, reboot_reasons: Vec::new() }
+	}
+
+	fn reboot(reasons: &[RebootReason]) -> Self {
+		Self {
+			requires_reboot: true,
+			reboot_reasons: reasons.iter().map(ToString::to_string).collect(),
+		}
+	}
 }
 
 #[derive(Debug)]
 enum UpdateState {
 	UpToDate,
 	Processing(ProcessState),
+	Available,
 	Ready(UpgradeReadyInfo),
 	Error(UpdateError),
 }
@@ -66,19 +139,87 @@ impl UpdateState {
 		match self {
 			UpToDate => "up_to_date",
 			Processing(_) => "processing",
+			Available => "available",
 			Ready(_) => "ready",
 			Error(_) => "error",
 		}
 	}
+
+	/// state to show while the upgrade process is in `s`, None if the upgrade process
+	/// is done or `s` does not change what is shown
+	fn from_upgrade_state(s: &UpgradeState) -> Option<Self> {
+		use UpdateState::*;
+		Some(match s {
+			UpgradeState::UpdatingInputs => Processing(ProcessState::Updating),
+			UpgradeState::InputsUpdated(_) => return None,
+			UpgradeState::CheckingUpgrades => Processing(ProcessState::Checking),
+			UpgradeState::NewSystem(_) => return None,
+			UpgradeState::BuildingOutput => Processing(ProcessState::Building),
+			UpgradeState::UpgradeAvailable => Available,
+			UpgradeState::RequiresSwitch => Ready(UpgradeReadyInfo::switch()),
+			UpgradeState::RequiresReboot(reasons) => Ready(UpgradeReadyInfo::reboot(reasons)),
+			UpgradeState::VerifyingSignatures => Processing(ProcessState::Verifying),
+			UpgradeState::SwitchingConfiguration => Processing(ProcessState::Switching),
+			UpgradeState::CheckingHealth => Processing(ProcessState::CheckingHealth),
+			UpgradeState::RollingBack => Processing(ProcessState::RollingBack),
+			UpgradeState::RolledBack => return None,
+			UpgradeState::SwitchingBoot => Processing(ProcessState::SettingBoot),
+			UpgradeState::Rebooting => Processing(ProcessState::Rebooting),
+			UpgradeState::Done => return None,
+		})
+	}
 }
 
 struct DaemonState {
 	update_state: UpdateState,
+	config: Config,
+	config_path: PathBuf,
+	/// cancels the running upgrade process, if any, and the action needed to start it, which cancelling needs too
+	upgrade: Option<(CancellationToken, Action)>,
+	/// progress of the nix command the upgrade process runs
+	progress: Progress,
+	/// inputs changed or held back by the last update
+	input_update: InputUpdate,
+	/// why the last update failed, None while no update has failed since the last one started
+	last_error: Option<ErrorDetails>,
+	logs: RunLogs,
+	history: History,
+	scheduler: Scheduler,
+	/// wakes up the scheduler task when the schedule has changed
+	replan: Arc<Notify>,
 }
 
 impl DaemonState {
-	fn initial() -> Self {
-		Self { update_state: UpdateState::UpToDate }
+	fn initial(config_path: &FsPath) -> Result<Self, ConfigError> {
+		Ok(Self {
+			update_state: UpdateState::UpToDate,
+			config: Config::load(config_path)?,
+			config_path: config_path.into(),
+			upgrade: None,
+			progress: Progress::default(),
+			input_update: InputUpdate::default(),
+			last_error: None,
+			logs: RunLogs::new(FsPath::new(RunLogs::DIR)),
+			history: History::new(FsPath::new(consts::STATE_DIR)),
+			scheduler: Scheduler::new(FsPath::new(consts::STATE_DIR)),
+			replan: Arc::new(Notify::new()),
+		})
+	}
+
+	/// Writes `record` to the history, replacing what was written for the same run before.
+	fn save_run(&self, record: &RunRecord) {
+		if let Err(e) = self.history.append(record) {
+			warn!("could not add update to history: {}", e);
+		}
+	}
+
+	/// Replaces the config with the file's current content. On error the old
+	/// config stays in place, a running upgrade keeps using the config it was started with.
+	fn reload_config(&mut self) -> Result<(), ConfigError> {
+		self.config = Config::load(&self.config_path)?;
+		info!("reloaded config from {}", self.config_path.display());
+		self.replan.notify_one();
+		Ok(())
 	}
 }
 
@@ -86,19 +227,118 @@ type SyncedDaemonState = Arc<Mutex<DaemonState>>;
 
 type DbusPropFun = Box<dyn Fn(&Path<'_>, &dyn RefArg) -> Option<Message> + Send + Sync + 'static>;
 struct DbusProperties {
-	version: DbusPropFun,
 	update_state: DbusPropFun,
 	process_state: DbusPropFun,
+	update_error: DbusPropFun,
+	last_error: DbusPropFun,
+	requires_reboot: DbusPropFun,
+	reboot_reasons: DbusPropFun,
+	next_scheduled_run: DbusPropFun,
+	last_run: DbusPropFun,
+	build_progress: DbusPropFun,
+	input_changes: DbusPropFun,
+	held_inputs: DbusPropFun,
+}
+
+/// D-Bus representation of [`Progress`]
+type DbusProgress = interface::Progress;
+
+fn dbus_progress(p: &Progress) -> DbusProgress {
+	(p.builds_done, p.builds_expected, p.bytes_downloaded, p.bytes_expected,
+		p.current_build.clone().unwrap_or_default())
+}
+
+/// D-Bus representation of a [`crate::nix::diff::PackageChange`]
+type DbusPackageChange = interface::PackageChange;
+
+fn dbus_changes(diff: &ClosureDiff) -> Vec<DbusPackageChange> {
+	diff.changes.iter()
+		.map(|c| (c.name.clone(), c.old_versions.clone(), c.new_versions.clone(), c.size_delta))
+		.collect()
+}
+
+/// D-Bus representation of an [`InputChange`], the URL is the new one
+type DbusInputChange = interface::InputChange;
+
+fn dbus_input_changes(changes: &[InputChange]) -> Vec<DbusInputChange> {
+	let rev = |i: &Option<LockedInput>| i.as_ref().and_then(|i| i.rev.clone()).unwrap_or_default();
+	let modified = |i: &Option<LockedInput>| i.as_ref().and_then(|i| i.last_modified).unwrap_or(0);
+	changes.iter()
+		.map(|c| (c.name.clone(), rev(&c.old), rev(&c.new), modified(&c.old), modified(&c.new),
+			c.new.as_ref().or(c.old.as_ref()).map(|i| i.url.clone()).unwrap_or_default()))
+		.collect()
+}
+
+/// D-Bus representation of a [`Generation`]
+type DbusGeneration = interface::Generation;
+
+fn dbus_generations(generations: &[Generation]) -> Vec<DbusGeneration> {
+	generations.iter()
+		.map(|g| (g.number, g.path.to_string(), g.created, g.label.clone().unwrap_or_default(),
+			g.kernel.clone().unwrap_or_default(), g.current))
+		.collect()
+}
+
+/// D-Bus representation of a [`RunInfo`]
+type DbusRunInfo = interface::RunInfo;
+
+fn dbus_runs(runs: &[RunInfo]) -> Vec<DbusRunInfo> {
+	runs.iter().map(|r| (r.id.clone(), r.modified, r.size)).collect()
+}
+
+/// D-Bus representation of a [`RunRecord`]
+type DbusHistoryEntry = interface::HistoryEntry;
+
+fn dbus_history(records: &[RunRecord]) -> Vec<DbusHistoryEntry> {
+	let path = |p: &Option<StorePath>| p.as_ref().map(ToString::to_string).unwrap_or_default();
+	records.iter()
+		.map(|r| (r.id.clone(), r.started, r.finished, r.trigger.to_str().to_string(), r.target.to_str().to_string(),
+			r.state.clone(), r.stage.clone().unwrap_or_default(), path(&r.old_system), path(&r.new_system),
+			dbus_input_changes(&r.input_changes), r.error.clone().unwrap_or_default()))
+		.collect()
+}
+
+/// most bytes of a run log GetRunLog returns at once
+const RUN_LOG_CHUNK: usize = 64 * 1024;
+
+/// Reply to a method call failing with the error `name`, one of [`interface::error`].
+fn named_err(name: &'static str, msg: impl std::fmt::Display) -> MethodErr {
+	(name, msg.to_string()).into()
+}
+
+/// Details of `e` for the LastError property, `code` is its UpdateError, `stage` the last
+/// ProcessState before it happened and `run_id` the run whose log tells more.
+fn error_details(e: &UpgradeError, code: &UpdateError, stage: Option<&str>, run_id: Option<String>) -> ErrorDetails {
+	let failure = e.command_failure();
+	ErrorDetails {
+		code: code.to_str().to_string(),
+		stage: stage.unwrap_or_default().to_string(),
+		message: e.to_string(),
+		exit_status: e.exit_status().and_then(|s| s.code()),
+		stderr: failure.map(|f| f.stderr.clone()).unwrap_or_default(),
+		derivation: failure.and_then(|f| f.failed_derivation()).map(str::to_string),
+		run_id,
+	}
+}
+
+/// D-Bus representation of a point in time, seconds since the epoch or 0 for none
+fn timestamp(t: Option<DateTime<Local>>) -> i64 {
+	t.map_or(0, |t| t.timestamp())
 }
 
 impl DbusProperties {
 	fn new(b: &mut IfaceBuilder<SyncedDaemonState>) -> Self {
-		Self {
-			version: b.property::<String, _>("Version")
-				.get(|_ctx: &mut PropContext, mh: &mut SyncedDaemonState| {
-					Ok(clap::crate_version!().to_string())
-				}).changed_msg_fn(),
+		b.property::<String, _>("Version")
+			.get(|_ctx: &mut PropContext, _mh: &mut SyncedDaemonState| {
+				Ok(clap::crate_version!().to_string())
+			}).emits_changed_const();
+
+		b.property::<u32, _>("InterfaceVersion")
+			.get(|_ctx: &mut PropContext, _mh: &mut SyncedDaemonState| {
+				Ok(interface::INTERFACE_VERSION)
+			}).emits_changed_const();
 
+		Self {
 			update_state: b.property::<String, _>("UpdateState")
 				.get(|_ctx: &mut PropContext, mh: &mut SyncedDaemonState| {
 					Ok(mh.lock().unwrap().update_state.to_str().to_string())
@@ -109,24 +349,583 @@ impl DbusProperties {
 					let ds = &mh.lock().unwrap().update_state;
 					match ds {
 						UpdateState::Processing(ps) => Ok(ps.to_str().to_string()),
-						_ => Err(MethodErr::failed("no update is being processed")),
+						_ => Err(named_err(error::NO_VALUE, "no update is being processed")),
 					}
 				}).changed_msg_fn(),
+
+			update_error: b.property::<String, _>("UpdateError")
+				.get(|_ctx: &mut PropContext, mh: &mut SyncedDaemonState| {
+					let ds = &mh.lock().unwrap().update_state;
+					match ds {
+						UpdateState::Error(e) => Ok(e.to_str().to_string()),
+						_ => Err(named_err(error::NO_VALUE, "last update did not fail")),
+					}
+				}).changed_msg_fn(),
+
+			last_error: b.property::<PropMap, _>("LastError")
+				.get(|_ctx: &mut PropContext, mh: &mut SyncedDaemonState| {
+					Ok(mh.lock().unwrap().last_error.as_ref().map(ErrorDetails::to_dict).unwrap_or_default())
+				}).changed_msg_fn(),
+
+			requires_reboot: b.property::<bool, _>("RequiresReboot")
+				.get(|_ctx: &mut PropContext, mh: &mut SyncedDaemonState| {
+					let ds = &mh.lock().unwrap().update_state;
+					match ds {
+						UpdateState::Ready(info) => Ok(info.requires_reboot),
+						_ => Err(named_err(error::NO_VALUE, "no update is ready")),
+					}
+				}).changed_msg_fn(),
+
+			reboot_reasons: b.property::<Vec<String>, _>("RebootReasons")
+				.get(|_ctx: &mut PropContext, mh: &mut SyncedDaemonState| {
+					let ds = &mh.lock().unwrap().update_state;
+					match ds {
+						UpdateState::Ready(info) => Ok(info.reboot_reasons.clone()),
+						_ => Err(named_err(error::NO_VALUE, "no update is ready")),
+					}
+				}).changed_msg_fn(),
+
+			next_scheduled_run: b.property::<i64, _>("NextScheduledRun")
+				.get(|_ctx: &mut PropContext, mh: &mut SyncedDaemonState| {
+					Ok(timestamp(mh.lock().unwrap().scheduler.next_run()))
+				}).changed_msg_fn(),
+
+			last_run: b.property::<i64, _>("LastRun")
+				.get(|_ctx: &mut PropContext, mh: &mut SyncedDaemonState| {
+					Ok(timestamp(mh.lock().unwrap().scheduler.last_run()))
+				}).changed_msg_fn(),
+
+			input_changes: b.property::<Vec<DbusInputChange>, _>("InputChanges")
+				.get(|_ctx: &mut PropContext, mh: &mut SyncedDaemonState| {
+					Ok(dbus_input_changes(&mh.lock().unwrap().input_update.changes))
+				}).changed_msg_fn(),
+
+			held_inputs: b.property::<Vec<String>, _>("HeldInputs")
+				.get(|_ctx: &mut PropContext, mh: &mut SyncedDaemonState| {
+					Ok(mh.lock().unwrap().input_update.held.clone())
+				}).changed_msg_fn(),
+
+			build_progress: b.property::<DbusProgress, _>("BuildProgress")
+				.get(|_ctx: &mut PropContext, mh: &mut SyncedDaemonState| {
+					Ok(dbus_progress(&mh.lock().unwrap().progress))
+				}).changed_msg_fn(),
 		}
 	}
 }
 
-fn check_root() {
+/// Sends PropertiesChanged signals from outside of method calls.
+struct Signaller {
+	con: Arc<SyncConnection>,
+	props: DbusProperties,
+	path: Path<'static>,
+}
+
+impl Signaller {
+	fn send(&self, msg: Option<Message>) {
+		if let Some(msg) = msg {
+			if self.con.send(msg).is_err() {
+				warn!("could not send PropertiesChanged signal");
+			}
+		}
+	}
+
+	fn set_update_state(&self, mh: &SyncedDaemonState, state: UpdateState) {
+		let mut ds = mh.lock().unwrap();
+		ds.update_state = state;
+		self.send((self.props.update_state)(&self.path, &ds.update_state.to_str().to_string()));
+		match &ds.update_state {
+			UpdateState::Processing(ps) =>
+				self.send((self.props.process_state)(&self.path, &ps.to_str().to_string())),
+			UpdateState::Error(e) =>
+				self.send((self.props.update_error)(&self.path, &e.to_str().to_string())),
+			UpdateState::Ready(info) => {
+				self.send((self.props.requires_reboot)(&self.path, &info.requires_reboot));
+				self.send((self.props.reboot_reasons)(&self.path, &info.reboot_reasons));
+			},
+			UpdateState::UpToDate | UpdateState::Available => (),
+		}
+	}
+
+	fn set_last_error(&self, mh: &SyncedDaemonState, details: Option<ErrorDetails>) {
+		let mut ds = mh.lock().unwrap();
+		ds.last_error = details;
+		let dict = ds.last_error.as_ref().map(ErrorDetails::to_dict).unwrap_or_default();
+		self.send((self.props.last_error)(&self.path, &dict));
+	}
+
+	fn schedule_changed(&self, ds: &DaemonState) {
+		self.send((self.props.next_scheduled_run)(&self.path, &timestamp(ds.scheduler.next_run())));
+		self.send((self.props.last_run)(&self.path, &timestamp(ds.scheduler.last_run())));
+	}
+
+	fn set_input_update(&self, mh: &SyncedDaemonState, update: &InputUpdate) {
+		let mut ds = mh.lock().unwrap();
+		ds.input_update = update.clone();
+		self.send((self.props.input_changes)(&self.path, &dbus_input_changes(&update.changes)));
+		self.send((self.props.held_inputs)(&self.path, &update.held));
+	}
+
+	fn set_progress(&self, mh: &SyncedDaemonState, progress: Progress) {
+		let mut ds = mh.lock().unwrap();
+		ds.progress = progress;
+		self.send((self.props.build_progress)(&self.path, &dbus_progress(&ds.progress)));
+	}
+}
+
+/// minimum time between two BuildProgress signals, nix reports progress many times a second
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Mirrors the progress of an upgrade process into the daemon state until the process ends.
+async fn forward_progress(mut progress: watch::Receiver<Progress>, mh: SyncedDaemonState, sig: Arc<Signaller>) {
+	loop {
+		let p = progress.borrow_and_update().clone();
+		sig.set_progress(&mh, p);
+		tokio::time::sleep(PROGRESS_INTERVAL).await;
+		if progress.changed().await.is_err() {
+			break;
+		}
+	}
+}
+
+/// Follows a running upgrade process and mirrors its progress into the daemon state.
+///
+/// The states the process passes through and its error are written to `log`, if there is one,
+/// and the run is kept in the history as `record`, updated before rebooting and once the process is done.
+async fn drive_upgrade(mut info: UpgradeProcessInfo, target: RunTo, log: Option<Arc<RunLog>>,
+		mut record: RunRecord, mh: SyncedDaemonState, sig: Arc<Signaller>) {
+	let write = |text: &str| if let Some(log) = &log {
+		log.write(text);
+	};
+	let mut states = info.out_queue.take().unwrap();
+	tokio::spawn(forward_progress(info.progress.clone(), Arc::clone(&mh), Arc::clone(&sig)));
+	sig.set_last_error(&mh, None);
+	write(&format!("# running to {:?}", target));
+	let mut last = None;
+	// last ProcessState shown, where a failure happened
+	let mut stage = None;
+	// state to show once the process is done
+	let mut done_state = UpdateState::UpToDate;
+	while let Some(state) = states.recv().await {
+		info!("upgrade process: {:?}", state);
+		match &state {
+			UpgradeState::InputsUpdated(update) => {
+				for c in &update.changes {
+					info!("updated {}", c);
+					write(&format!("# updated {}", c));
+				}
+				sig.set_input_update(&mh, update);
+				record.input_changes = update.changes.clone();
+			},
+			UpgradeState::NewSystem(path) => record.new_system = Some(path.clone()),
+			// the daemon may not get to record the end of the run
+			UpgradeState::Rebooting => {
+				record.stage = Some(ProcessState::Rebooting.to_str().to_string());
+				mh.lock().unwrap().save_run(&record);
+			},
+			UpgradeState::UpgradeAvailable => done_state = UpdateState::Available,
+			UpgradeState::RequiresSwitch | UpgradeState::RequiresReboot(_) => {
+				let reasons = match &state {
+					UpgradeState::RequiresReboot(reasons) => reasons.as_slice(),
+					_ => &[],
+				};
+				match target.command_for(&state) {
+					// the build is kept to be applied later
+					None => done_state = UpdateState::Ready(match state {
+						UpgradeState::RequiresReboot(_) => UpgradeReadyInfo::reboot(reasons),
+						_ => UpgradeReadyInfo::switch(),
+					}),
+					Some(cmd) => {
+						if cmd == RunTo::SetBoot {
+							done_state = UpdateState::Ready(UpgradeReadyInfo::reboot(reasons));
+						}
+						info.in_queue.send(cmd).unwrap();
+					},
+				}
+			},
+			_ => (),
+		}
+		if ! matches!(state, UpgradeState::InputsUpdated(_)) {
+			write(&format!("# {:?}", state));
+		}
+		if let Some(s) = UpdateState::from_upgrade_state(&state) {
+			if let UpdateState::Processing(ps) = &s {
+				stage = Some(ps.to_str());
+			}
+			sig.set_update_state(&mh, s);
+		}
+		last = Some(state);
+	}
+
+	let result = info.result.take().unwrap().await;
+	let run_id = log.as_ref().map(|l| l.id().to_string());
+	let new_state = match result {
+		Ok(Ok(())) => done_state,
+		Ok(Err(e @ UpgradeError::Cancelled)) => {
+			record.error = Some(e.to_string());
+			UpdateState::UpToDate
+		},
+		Ok(Err(e)) => {
+			error!("upgrade failed: {}", e);
+			record.error = Some(e.to_string());
+			write(&format!("# upgrade failed: {}", e));
+			let code = UpdateError::from_upgrade_error(&e, last.as_ref());
+			sig.set_last_error(&mh, Some(error_details(&e, &code, stage, run_id)));
+			UpdateState::Error(code)
+		},
+		Err(e) => {
+			error!("upgrade process panicked: {}", e);
+			record.error = Some(format!("upgrade process panicked: {}", e));
+			write(&format!("# upgrade process panicked: {}", e));
+			let code = UpdateError::BuildFailed;
+			sig.set_last_error(&mh, Some(ErrorDetails {
+				code: code.to_str().to_string(),
+				stage: stage.unwrap_or_default().to_string(),
+				message: format!("upgrade process panicked: {}", e),
+				run_id,
+				..Default::default()
+			}));
+			UpdateState::Error(code)
+		},
+	};
+	record.finished = Local::now().timestamp();
+	record.state = new_state.to_str().to_string();
+	record.stage = stage.map(str::to_string);
+	{
+		let mut ds = mh.lock().unwrap();
+		ds.upgrade = None;
+		ds.save_run(&record);
+	}
+	sig.set_update_state(&mh, new_state);
+}
+
+/// Starts an upgrade process running to `target`, or to the configured target if None.
+///
+/// `start` is [`UpgradeProcess::run`], [`UpgradeProcess::resume`] or [`UpgradeProcess::rollback`].
+/// `trigger` is recorded in the history.
+fn start_upgrade<F>(mh: &SyncedDaemonState, sig: &Arc<Signaller>, target: Option<RunTo>, trigger: Trigger, start: F)
+		-> Result<(), MethodErr>
+		where F: FnOnce(UpgradeProcess, RunTo) -> UpgradeProcessInfo {
+	let mut ds = mh.lock().unwrap();
+	if ds.upgrade.is_some() {
+		return Err(named_err(error::BUSY, "an update is already in progress"));
+	}
+	let target = target.unwrap_or(ds.config.target);
+	let mut process = UpgradeProcess::for_config(&ds.config);
+	let now = Local::now();
+	let log = match ds.logs.start(now) {
+		Ok(log) => Some(Arc::new(log)),
+		Err(e) => {
+			warn!("not keeping a log of this update: {}", e);
+			None
+		},
+	};
+	if let Some(log) = &log {
+		process = process.with_log(Arc::clone(log));
+	}
+	let id = log.as_ref().map_or_else(|| RunLogs::run_id(now), |l| l.id().to_string());
+	let mut record = RunRecord::new(id, now.timestamp(), trigger, target);
+	record.old_system = ds.config.profile().get_current().ok();
+	ds.save_run(&record);
+	let info = start(process, target);
+	ds.upgrade = Some((info.cancel.clone(), Action::for_target(target)));
+	tokio::spawn(drive_upgrade(info, target, log, record, Arc::clone(mh), Arc::clone(sig)));
+	Ok(())
+}
+
+/// Sleeps until the wall clock reaches `t`.
+///
+/// Checks the clock at least every minute, tokio's timers do not advance while the machine is suspended.
+async fn sleep_until(t: DateTime<Local>) {
+	while let Ok(left) = (t - Local::now()).to_std() {
+		tokio::time::sleep(left.min(Duration::from_secs(60))).await;
+	}
+}
+
+/// Confirms the booted system if it was booted on trial, see [`crate::trial::TrialBoot`].
+async fn confirm_boot(mh: SyncedDaemonState) {
+	let process = UpgradeProcess::for_config(&mh.lock().unwrap().config);
+	if let Err(e) = process.confirm_boot().await {
+		error!("{}", e);
+	}
+}
+
+/// pause before starting a scheduled update again that could not be started
+const SCHEDULE_RETRY_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Starts upgrade processes according to the configured schedule.
+async fn run_schedule(mh: SyncedDaemonState, sig: Arc<Signaller>) {
+	let replan = Arc::clone(&mh.lock().unwrap().replan);
+	loop {
+		let (next, schedule) = {
+			let mut ds = mh.lock().unwrap();
+			let schedule = ds.config.schedule.clone();
+			ds.scheduler.plan(schedule.as_ref(), Local::now());
+			sig.schedule_changed(&ds);
+			(ds.scheduler.next_run(), schedule)
+		};
+		let (Some(next), Some(schedule)) = (next, schedule) else {
+			replan.notified().await;
+			continue;
+		};
+		info!("next scheduled update at {}", next);
+
+		tokio::select! {
+			_ = sleep_until(next) => (),
+			_ = replan.notified() => continue,
+		}
+
+		if let Err(e) = start_upgrade(&mh, &sig, Some(schedule.target), Trigger::Schedule, UpgradeProcess::run) {
+			// the run stays due and is retried, e.g. once the running update is done
+			warn!("could not start scheduled update, retrying: {}", e.description());
+			tokio::select! {
+				_ = tokio::time::sleep(SCHEDULE_RETRY_INTERVAL) => (),
+				_ = replan.notified() => (),
+			}
+			continue;
+		}
+		if let Err(e) = mh.lock().unwrap().scheduler.record_run(Local::now()) {
+			warn!("could not save time of scheduled update: {}", e);
+		}
+	}
+}
+
+/// Switching configurations and the system bus name need root.
+fn check_root() -> anyhow::Result<()> {
 	use nix::unistd::Uid;
 	if ! Uid::effective().is_root() {
-		warn!("Deamon not running as root.");
+		anyhow::bail!("the daemon must run as root");
 	}
+	Ok(())
+}
+
+/// Unique bus name of the caller of a method.
+fn sender(ctx: &Context) -> Option<String> {
+	ctx.message().sender().map(|s| s.to_string())
+}
+
+/// What a method call of `sender` starts: [`Trigger::Cli`] if the caller runs the same
+/// program as the daemon, [`Trigger::User`] otherwise.
+async fn trigger_of(con: &SyncConnection, sender: Option<&str>) -> Trigger {
+	let Some(sender) = sender else {
+		return Trigger::User;
+	};
+	let proxy = Proxy::new("org.freedesktop.DBus", "/org/freedesktop/DBus", Duration::from_secs(5), con);
+	let pid: Result<(u32,), _> = proxy.method_call("org.freedesktop.DBus", "GetConnectionUnixProcessID", (sender,)).await;
+	// compared by name, the caller may run another version from a newer generation
+	let program = |pid: &str| std::fs::read_link(format!("/proc/{}/exe", pid)).ok()
+		.and_then(|p| p.file_name().map(|n| n.to_owned()));
+	match pid {
+		Ok((pid,)) if program(&pid.to_string()).is_some_and(|p| Some(p) == program("self")) => Trigger::Cli,
+		_ => Trigger::User,
+	}
+}
+
+/// Runs `f` once polkit authorized `sender` for `action`.
+async fn authorized<R>(con: &SyncConnection, sender: Option<String>, action: Action,
+		f: impl FnOnce() -> Result<R, MethodErr>) -> Result<R, MethodErr> {
+	let sender = sender.ok_or_else(|| named_err(error::AUTHORIZATION_FAILED, AuthError::NoSender))?;
+	match check_authorization(con, &sender, action).await {
+		Ok(()) => f(),
+		Err(e @ AuthError::NotAuthorized(_)) => {
+			info!("denied {} to {}", action.id(), sender);
+			Err(("org.freedesktop.DBus.Error.AccessDenied", e.to_string()).into())
+		},
+		Err(e) => Err(named_err(error::AUTHORIZATION_FAILED, e)),
+	}
+}
+
+/// Reloads the config whenever the daemon receives SIGHUP.
+async fn reload_on_sighup(mh: SyncedDaemonState) -> anyhow::Result<()> {
+	use tokio::signal::unix::{signal, SignalKind};
+	let mut hup = signal(SignalKind::hangup())?;
+	while hup.recv().await.is_some() {
+		if let Err(e) = mh.lock().unwrap().reload_config() {
+			error!("keeping old config: {}", e);
+		}
+	}
+	Ok(())
+}
+
+/// Adds a method without arguments that starts an update process running to `target` with `start`,
+/// see [`start_upgrade`].
+fn start_method(b: &mut IfaceBuilder<SyncedDaemonState>, name: &'static str, target: RunTo,
+		start: fn(UpgradeProcess, RunTo) -> UpgradeProcessInfo, sig: &Arc<Signaller>, con: &Arc<SyncConnection>) {
+	let (sig2, con2) = (Arc::clone(sig), Arc::clone(con));
+	b.method_with_cr_async(name, (), (), move |mut ctx, cr, _: ()| {
+		let mh: SyncedDaemonState = Arc::clone(cr.data_mut(ctx.path()).unwrap());
+		let (sig, con, sender) = (Arc::clone(&sig2), Arc::clone(&con2), sender(&ctx));
+		async move {
+			let trigger = trigger_of(&con, sender.as_deref()).await;
+			let r = authorized(&con, sender, Action::for_target(target),
+				|| start_upgrade(&mh, &sig, Some(target), trigger, start)).await;
+			ctx.reply(r)
+		}
+	});
+}
+
+/// Registers the de.afuchs.NixOSUpdater interface, described in `interface/de.afuchs.NixOSUpdater.xml`.
+fn register_interface(cr: &mut Crossroads, con: &Arc<SyncConnection>) -> (IfaceToken<SyncedDaemonState>, Arc<Signaller>) {
+	let mut signaller = None;
+	let token = cr.register(consts::NAME, |b| {
+		let sig = Arc::new(Signaller {
+			con: Arc::clone(con),
+			props: DbusProperties::new(b),
+			path: consts::PATH.into(),
+		});
+		signaller = Some(Arc::clone(&sig));
+
+		// a kept build is only applied by ApplyUpdate, it may be outdated by now
+		for (name, target) in [("Check", RunTo::Check), ("Build", RunTo::Build), ("Switch", RunTo::Switch),
+				("SetBoot", RunTo::SetBoot), ("Reboot", RunTo::Reboot)] {
+			start_method(b, name, target, UpgradeProcess::run, &sig, con);
+		}
+
+		let (sig2, con2) = (Arc::clone(&sig), Arc::clone(con));
+		b.method_with_cr_async("BuildUpdate", (), (), move |mut ctx, cr, _: ()| {
+			let mh: SyncedDaemonState = Arc::clone(cr.data_mut(ctx.path()).unwrap());
+			let target = mh.lock().unwrap().config.target;
+			let (sig, con, sender) = (Arc::clone(&sig2), Arc::clone(&con2), sender(&ctx));
+			async move {
+				let trigger = trigger_of(&con, sender.as_deref()).await;
+				let r = authorized(&con, sender, Action::for_target(target),
+					|| start_upgrade(&mh, &sig, Some(target), trigger, UpgradeProcess::run)).await;
+				ctx.reply(r)
+			}
+		});
+
+		let (sig2, con2) = (Arc::clone(&sig), Arc::clone(con));
+		b.method_with_cr_async("ApplyUpdate", ("target",), (), move |mut ctx, cr, (target,): (String,)| {
+			let mh: SyncedDaemonState = Arc::clone(cr.data_mut(ctx.path()).unwrap());
+			let (sig, con, sender) = (Arc::clone(&sig2), Arc::clone(&con2), sender(&ctx));
+			async move {
+				let trigger = trigger_of(&con, sender.as_deref()).await;
+				let r = match RunTo::from_str(&target) {
+					Ok(t @ (RunTo::Switch | RunTo::SetBoot | RunTo::Reboot)) =>
+						authorized(&con, sender, Action::for_target(t),
+							|| start_upgrade(&mh, &sig, Some(t), trigger, UpgradeProcess::resume)).await,
+					_ => Err(MethodErr::invalid_arg("target must be switch, set_boot or reboot")),
+				};
+				ctx.reply(r)
+			}
+		});
+
+		b.method("ListGenerations", (), ("generations",), |_ctx, mh: &mut SyncedDaemonState, _: ()| {
+			let profile = mh.lock().unwrap().config.profile();
+			let generations = profile.generations().map_err(|e| named_err(error::FAILED, e))?;
+			Ok((dbus_generations(&generations),))
+		});
+
+		b.method("PreviewGarbageCollection", (), ("generations",), |_ctx, mh: &mut SyncedDaemonState, _: ()| {
+			let process = UpgradeProcess::for_config(&mh.lock().unwrap().config);
+			let deleted = process.garbage_preview().map_err(|e| named_err(error::FAILED, e))?;
+			Ok((deleted,))
+		});
+
+		let (sig2, con2) = (Arc::clone(&sig), Arc::clone(con));
+		b.method_with_cr_async("RollbackTo", ("generation", "mode"), (), move |mut ctx, cr, (generation, mode): (u32, String)| {
+			let mh: SyncedDaemonState = Arc::clone(cr.data_mut(ctx.path()).unwrap());
+			let (sig, con, sender) = (Arc::clone(&sig2), Arc::clone(&con2), sender(&ctx));
+			async move {
+				let trigger = trigger_of(&con, sender.as_deref()).await;
+				let r = match RunTo::from_str(&mode) {
+					Ok(t @ (RunTo::Switch | RunTo::SetBoot | RunTo::Reboot)) =>
+						authorized(&con, sender, Action::for_target(t),
+							|| start_upgrade(&mh, &sig, Some(t), trigger, |p, t| p.rollback(generation, t))).await,
+					_ => Err(MethodErr::invalid_arg("mode must be switch, set_boot or reboot")),
+				};
+				ctx.reply(r)
+			}
+		});
+
+		let con2 = Arc::clone(con);
+		b.method_with_cr_async("Cancel", (), (), move |mut ctx, cr, _: ()| {
+			let mh: SyncedDaemonState = Arc::clone(cr.data_mut(ctx.path()).unwrap());
+			let (con, sender) = (Arc::clone(&con2), sender(&ctx));
+			async move {
+				let not_running = || named_err(error::NOT_RUNNING, "no update is in progress");
+				let action = mh.lock().unwrap().upgrade.as_ref().map(|(_, action)| *action);
+				let r = match action {
+					Some(action) => authorized(&con, sender, action, || match &mh.lock().unwrap().upgrade {
+						// not one started while polkit was asked
+						Some((cancel, a)) if *a == action => {
+							info!("cancelling upgrade process");
+							cancel.cancel();
+							Ok(())
+						},
+						_ => Err(not_running()),
+					}).await,
+					None => Err(not_running()),
+				};
+				ctx.reply(r)
+			}
+		});
+
+		b.method_with_cr_async("GetChangeSummary", (), ("changes", "size_delta"), |mut ctx, cr, _: ()| {
+			let mh: &mut SyncedDaemonState = cr.data_mut(ctx.path()).unwrap();
+			let process = UpgradeProcess::for_config(&mh.lock().unwrap().config);
+			async move {
+				let summary = process.change_summary().await
+					.map(|diff| (dbus_changes(&diff), diff.size_delta()))
+					.map_err(|e| match e {
+						DiffError::NoPendingBuild | DiffError::StorePathError(_) => named_err(error::NOTHING_BUILT, e),
+						e => named_err(error::FAILED, e),
+					});
+				ctx.reply(summary)
+			}
+		});
+
+		let con2 = Arc::clone(con);
+		b.method_with_cr_async("ReloadConfig", (), (), move |mut ctx, cr, _: ()| {
+			let mh: SyncedDaemonState = Arc::clone(cr.data_mut(ctx.path()).unwrap());
+			let (con, sender) = (Arc::clone(&con2), sender(&ctx));
+			async move {
+				let r = authorized(&con, sender, Action::Configure,
+					|| mh.lock().unwrap().reload_config().map_err(|e| named_err(error::INVALID_CONFIG, e))).await;
+				ctx.reply(r)
+			}
+		});
+
+		b.method("ListRuns", (), ("runs",), |_ctx, mh: &mut SyncedDaemonState, _: ()| {
+			let runs = mh.lock().unwrap().logs.list().map_err(|e| named_err(error::FAILED, e))?;
+			Ok((dbus_runs(&runs),))
+		});
+
+		b.method("GetRunLog", ("run_id", "offset"), ("data", "next_offset"),
+				|_ctx, mh: &mut SyncedDaemonState, (run_id, offset): (String, u64)| {
+			let logs = mh.lock().unwrap().logs.clone();
+			logs.read(&run_id, offset, RUN_LOG_CHUNK).map_err(|e| match e.kind() {
+				std::io::ErrorKind::InvalidInput => MethodErr::invalid_arg(&run_id),
+				_ => named_err(error::FAILED, e),
+			})
+		});
+
+		b.method("GetHistory", ("limit",), ("history",), |_ctx, mh: &mut SyncedDaemonState, (limit,): (u32,)| {
+			let records = mh.lock().unwrap().history.read(limit as usize).map_err(|e| named_err(error::FAILED, e))?;
+			Ok((dbus_history(&records),))
+		});
+
+		b.method_with_cr_async("GetDerivationLog", ("drv",), ("log",), |mut ctx, _cr, (drv,): (String,)| {
+			async move {
+				let r = match StorePath::new(FsPath::new(&drv)) {
+					Ok(path) if drv.ends_with(".drv") => derivation_log(&path, &CancellationToken::new()).await
+						.map(|log| (log,))
+						.map_err(|e| named_err(error::FAILED, e)),
+					_ => Err(MethodErr::invalid_arg("drv must be a derivation in the nix store")),
+				};
+				ctx.reply(r)
+			}
+		});
+	});
+	(token, signaller.unwrap())
 }
 
-pub async fn main() -> anyhow::Result<()> {
-	check_root();
+pub async fn main(config_path: &FsPath) -> anyhow::Result<()> {
+	check_root()?;
 
-	let (resource, con) = connection::new_session_sync()?;
+	let mh: SyncedDaemonState = Arc::new(Mutex::new(DaemonState::initial(config_path)?));
+	tokio::spawn(reload_on_sighup(Arc::clone(&mh)));
+
+	let (resource, con) = connection::new_system_sync()?;
 
 	// spawn , will only finish on error
 	let _handle = tokio::spawn(async {
@@ -138,36 +937,15 @@ pub async fn main() -> anyhow::Result<()> {
 	con.request_name(consts::NAME, false, true, false).await?;
 
 	let mut cr = Crossroads::new();
-	
+
 	// tell Crossroads how to spawn tasks
 	cr.set_async_support(Some((con.clone(), Box::new(|x| { tokio::spawn(x); }))));
 
-	let iface_token = cr.register(consts::NAME, |b| {
-		let props = Arc::new(DbusProperties::new(b));
-		
-		b.method_with_cr_async("BuildUpdate", (), (), move |mut ctx, cr, _: ()| {
-			let mh: SyncedDaemonState = Arc::clone(&cr.data_mut(ctx.path()).unwrap());
-			let props = Arc::clone(&props);
+	let (iface_token, signaller) = register_interface(&mut cr, &con);
 
-			async move {
-				{
-					let mut ds = mh.lock().unwrap();
-					ds.update_state = UpdateState::Processing(ProcessState::Building);
-					ctx.push_msg((props.update_state)(ctx.path(), &ds.update_state.to_str().to_string()).unwrap());
-				}
-				sleep(Duration::from_millis(3000)).await;
-				{
-					let mut ds = mh.lock().unwrap();
-					ds.update_state = UpdateState::UpToDate;
-					ctx.push_msg((props.update_state)(ctx.path(), &ds.update_state.to_str().to_string()).unwrap());
-				}
-
-				ctx.reply(Ok(()))
-			}
-		});
-	});
-
-	cr.insert("/de/afuchs/NixOSUpdater", &[iface_token], Arc::new(Mutex::new(DaemonState::initial())));
+	tokio::spawn(confirm_boot(Arc::clone(&mh)));
+	tokio::spawn(run_schedule(Arc::clone(&mh), signaller));
+	cr.insert(consts::PATH, &[iface_token], mh);
 	con.start_receive(MatchRule::new_method_call(), Box::new(move |msg, conn| {
 		cr.handle_message(msg, conn).unwrap();
 		true
@@ -177,3 +955,107 @@ pub async fn main() -> anyhow::Result<()> {
 	unreachable!()
 }
 
+#[cfg(test)]
+pub(crate) mod tests {
+	use super::*;
+	use dbus::channel::Channel;
+	use std::io::{BufRead, BufReader};
+	use std::process::{Child, Command, Stdio};
+
+	/// A private bus, stopped when dropped.
+	pub(crate) struct TestBus {
+		daemon: Child,
+		pub address: String,
+	}
+
+	impl TestBus {
+		pub fn start() -> Self {
+			let mut daemon = Command::new("dbus-daemon")
+				.args(["--session", "--nofork", "--print-address"])
+				.stdout(Stdio::piped())
+				.stderr(Stdio::null())
+				.spawn()
+				.expect("dbus-daemon is needed to test the D-Bus interface");
+			let mut address = String::new();
+			BufReader::new(daemon.stdout.as_mut().unwrap()).read_line(&mut address).unwrap();
+			Self { daemon, address: address.trim().to_string() }
+		}
+	}
+
+	impl Drop for TestBus {
+		fn drop(&mut self) {
+			let _ = self.daemon.kill();
+			let _ = self.daemon.wait();
+		}
+	}
+
+	/// Lines of the de.afuchs.NixOSUpdater interface element without comments, indentation
+	/// and blank lines, grouped by member and sorted.
+	fn interface_members(xml: &str) -> Vec<Vec<String>> {
+		let mut text = xml.to_string();
+		while let Some(start) = text.find("<!--") {
+			let end = text[start..].find("-->").expect("unterminated comment") + start;
+			text.replace_range(start..end + 3, "");
+		}
+		let start = text.find(&format!("<interface name=\"{}\">", consts::NAME)).expect("interface not found");
+		let end = text[start..].find("</interface>").unwrap() + start;
+
+		let mut members: Vec<Vec<String>> = Vec::new();
+		for line in text[start..end].lines().skip(1).map(str::trim).filter(|l| ! l.is_empty()) {
+			if ["<method ", "<signal ", "<property "].iter().any(|m| line.starts_with(m)) || members.is_empty() {
+				members.push(Vec::new());
+			}
+			members.last_mut().unwrap().push(line.to_string());
+		}
+		members.sort();
+		members
+	}
+
+	#[test]
+	fn last_error_details() {
+		use crate::errors::{BuildError, CommandFailure};
+		use std::os::unix::process::ExitStatusExt;
+
+		let e = UpgradeError::from(BuildError::NixCommandFailed(CommandFailure {
+			status: std::process::ExitStatus::from_raw(1 << 8),
+			stderr: vec!["error: builder for '/nix/store/k6qyppd2y8yamyx7vrq3zd9vac5hgc5n-hello-2.12.1.drv' failed with exit code 2".to_string()],
+		}));
+		let details = error_details(&e, &UpdateError::BuildFailed, Some(ProcessState::Building.to_str()),
+			Some("20240312-040000".to_string()));
+		assert_eq!(details.code, "build_failed");
+		assert_eq!(details.stage, "building");
+		assert_eq!(details.exit_status, Some(1));
+		assert_eq!(details.derivation.as_deref(), Some("/nix/store/k6qyppd2y8yamyx7vrq3zd9vac5hgc5n-hello-2.12.1.drv"));
+
+		let msg = Message::new_signal(consts::PATH, consts::NAME, "Test").unwrap().append1(details.to_dict());
+		let dict: PropMap = msg.read1().unwrap();
+		assert_eq!(ErrorDetails::from_dict(&dict), Some(details));
+		assert_eq!(ErrorDetails::from_dict(&PropMap::new()), None);
+	}
+
+	#[tokio::test]
+	async fn introspection_matches_xml() {
+		let bus = TestBus::start();
+		let mut channel = Channel::open_private(&bus.address).unwrap();
+		channel.register().unwrap();
+		let (resource, con) = connection::from_channel::<SyncConnection>(channel).unwrap();
+		tokio::spawn(resource);
+
+		let config = mktemp::Temp::new_file().unwrap();
+		std::fs::write(&config, r#"flake = "/etc/nixos""#).unwrap();
+		let mh: SyncedDaemonState = Arc::new(Mutex::new(DaemonState::initial(&config).unwrap()));
+		let mut cr = Crossroads::new();
+		cr.set_async_support(Some((con.clone(), Box::new(|x| { tokio::spawn(x); }))));
+		let (iface_token, _) = register_interface(&mut cr, &con);
+		cr.insert(consts::PATH, &[iface_token, cr.introspectable()], mh);
+		con.start_receive(MatchRule::new_method_call(), Box::new(move |msg, conn| {
+			cr.handle_message(msg, conn).unwrap();
+			true
+		}));
+
+		let proxy = Proxy::new(con.unique_name().to_string(), consts::PATH, Duration::from_secs(5), con.clone());
+		let (served,): (String,) = proxy.method_call("org.freedesktop.DBus.Introspectable", "Introspect", ())
+			.await.unwrap();
+		assert_eq!(interface_members(&served), interface_members(interface::INTERFACE_XML));
+	}
+}
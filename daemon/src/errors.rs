@@ -1,5 +1,46 @@
 use thiserror::Error;
 use std::io;
+use std::process::ExitStatus;
+
+/// How a command that ran failed.
+#[derive(Debug)]
+pub struct CommandFailure {
+	pub status: ExitStatus,
+	/// last lines written to stderr, the messages nix logged for nix commands
+	pub stderr: Vec<String>,
+}
+
+impl CommandFailure {
+	/// Derivation nix reported as failed, e.g. in "builder for '/nix/store/…-hello.drv' failed with exit code 1".
+	pub fn failed_derivation(&self) -> Option<&str> {
+		self.stderr.iter().find_map(|line| {
+			["builder for '", "Cannot build '"].into_iter().find_map(|prefix| {
+				let (_, rest) = line.split_once(prefix)?;
+				let (drv, _) = rest.split_once('\'')?;
+				drv.ends_with(".drv").then_some(drv)
+			})
+		})
+	}
+}
+
+impl std::fmt::Display for CommandFailure {
+	fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+		match self.stderr.last() {
+			Some(line) => write!(f, "{}: {}", self.status, line),
+			None => write!(f, "{}", self.status),
+		}
+	}
+}
+
+#[derive(Debug, Error)]
+pub enum CommandError {
+	#[error("could not run command: {}", .0)]
+	IOError(#[from] io::Error),
+	#[error("command failed: {}", .0)]
+	Failed(CommandFailure),
+	#[error("command was cancelled")]
+	Cancelled,
+}
 
 #[derive(Debug, Error)]
 pub enum StorePathError {
@@ -15,43 +56,236 @@ pub enum BuildError {
 	StorePathError(#[from] StorePathError),
 	#[error("build error: {}", .0)]
 	IOError(#[from] io::Error),
-	#[error("nix command failed")]
-	NixCommandFailed,
+	#[error("nix command failed: {}", .0)]
+	NixCommandFailed(CommandFailure),
 	#[error("flake has no attribute")]
 	FlakeHasNoAttr,
 	#[error("nix build --json output could not be parsed: {}", .0)]
 	ParsingNixBuildJSONFailed(serde_json::Error),
 	#[error("nix build --dry-run produced unexepcted output: {}", .0)]
 	DryRunProducedUnexpected(String),
+	#[error("{} is not available from any configured substituter", .0)]
+	NotInSubstituters(String),
+	#[error("build cancelled")]
+	Cancelled,
+}
+
+impl From<CommandError> for BuildError {
+	fn from(e: CommandError) -> Self {
+		match e {
+			CommandError::IOError(e) => Self::IOError(e),
+			CommandError::Failed(f) => Self::NixCommandFailed(f),
+			CommandError::Cancelled => Self::Cancelled,
+		}
+	}
+}
+
+#[derive(Debug, Error)]
+pub enum GitError {
+	#[error("git failed: {}", .0)]
+	CommandFailed(#[from] CommandError),
+	#[error("could not access repository: {}", .0)]
+	IOError(#[from] io::Error),
+	#[error("repository owner {} unknown: {}", .0, .1)]
+	NoOwner(u32, String),
+	#[error("path {} is not valid unicode", .0.display())]
+	InvalidPath(std::path::PathBuf),
 }
 
 #[derive(Debug, Error)]
 pub enum UpdateError {
 	#[error("update failed: {}", .0)]
 	IOError(#[from] io::Error),
-	#[error("nix command failed")]
-	NixCommandFailed,
+	#[error("nix command failed: {}", .0)]
+	NixCommandFailed(CommandFailure),
+	#[error("flake.lock could not be parsed: {}", .0)]
+	ParsingLockFileFailed(serde_json::Error),
+	#[error("published closure is invalid: {}", .0)]
+	StorePathError(#[from] StorePathError),
+	#[error("could not commit flake.lock: {}", .0)]
+	GitError(#[from] GitError),
+	#[error("update cancelled")]
+	Cancelled,
+}
+
+impl From<CommandError> for UpdateError {
+	fn from(e: CommandError) -> Self {
+		match e {
+			CommandError::IOError(e) => Self::IOError(e),
+			CommandError::Failed(f) => Self::NixCommandFailed(f),
+			CommandError::Cancelled => Self::Cancelled,
+		}
+	}
+}
+
+#[derive(Debug, Error)]
+pub enum DiffError {
+	#[error("nix store diff-closures failed: {}", .0)]
+	CommandFailed(#[from] CommandError),
+	#[error("unexpected diff-closures output: {}", .0)]
+	UnexpectedOutput(String),
+	#[error("no built update to compare: {}", .0)]
+	StorePathError(#[from] StorePathError),
+	#[error("no built update to compare")]
+	NoPendingBuild,
+}
+
+#[derive(Debug, Error)]
+#[error("not enough space on {}: {} MiB needed, {} MiB free", .path.display(), .needed / (1024 * 1024), .available / (1024 * 1024))]
+pub struct SpaceError {
+	pub path: std::path::PathBuf,
+	pub needed: u64,
+	pub available: u64,
+}
+
+#[derive(Debug, Error)]
+pub enum GcError {
+	#[error("garbage collection failed: {}", .0)]
+	CommandFailed(#[from] CommandError),
+	#[error("could not read generations: {}", .0)]
+	StorePathError(#[from] StorePathError),
+}
+
+#[derive(Debug, Error)]
+pub enum VerifyError {
+	#[error("nix store verify failed: {}", .0)]
+	CommandFailed(#[from] CommandError),
+	#[error("closure is not signed by enough trusted keys, {} untrusted paths", .0.len())]
+	Untrusted(Vec<String>),
+}
+
+#[derive(Debug, Error)]
+pub enum HealthError {
+	#[error("could not run health check: {}", .0)]
+	CommandFailed(#[from] CommandError),
+	#[error("unexpected systemctl output: {}", .0)]
+	UnexpectedOutput(String),
+	#[error("unit {} is not active", .0)]
+	UnitInactive(String),
+	#[error("failed units increased from {} to {}", .0, .1)]
+	UnitsFailed(u32, u32),
+	#[error("host {} is not reachable", .0)]
+	Unreachable(String),
+	#[error("health check {} failed: {}", .0, .1)]
+	CheckFailed(String, ExitStatus),
+	#[error("health checks did not finish within {}s", .0)]
+	TimedOut(u64),
+}
+
+#[derive(Debug, Error)]
+pub enum ConfirmError {
+	#[error("health check failed: {}", .0)]
+	Unhealthy(#[from] HealthError),
+	#[error("not confirmed within {} seconds", .0)]
+	DeadlineExceeded(u64),
+	#[error("gave up after {} boots", .0)]
+	AttemptsExhausted(u32),
+}
+
+#[derive(Debug, Error)]
+pub enum AuthError {
+	#[error("could not check authorization: {}", .0)]
+	DBusError(#[from] dbus::Error),
+	#[error("caller has no unique bus name")]
+	NoSender,
+	#[error("not authorized for {}", .0)]
+	NotAuthorized(&'static str),
+}
+
+#[derive(Debug, Error)]
+pub enum ConfigError {
+	#[error("could not read config file {}: {}", .0, .1)]
+	IOError(String, io::Error),
+	#[error("could not parse config file: {}", .0)]
+	ParseError(#[from] toml::de::Error),
+	#[error("invalid config value for {}: {}", .0, .1)]
+	Invalid(&'static str, String),
+	#[error("config_name not set and hostname unavailable: {}", .0)]
+	NoHostname(String),
 }
 
 #[derive(Debug, Error)]
 pub enum UpgradeError {
 	#[error("upgrade process failed: {}", .0)]
-	BuildError(#[from] BuildError),
+	BuildError(BuildError),
 	#[error("upgrade process failed: {}", .0)]
-	UpdateError(#[from] UpdateError),
+	UpdateError(UpdateError),
 	#[error("upgrade failed: {}", .0)]
 	StorePathError(#[from] StorePathError),
-	#[error("switch command failed: {:?}", .0)]
-	SwitchFailed(Option<io::Error>),
+	#[error("refusing to build: {}", .0)]
+	NotEnoughSpace(#[from] SpaceError),
+	#[error("refusing to switch: {}", .0)]
+	VerificationFailed(VerifyError),
+	#[error("switch command failed: {}", .0)]
+	SwitchFailed(CommandError),
+	#[error("rolled back after failed health check: {}", .0)]
+	RolledBack(HealthError),
+	#[error("booted system not confirmed, fell back to the previous one: {}", .0)]
+	FellBack(ConfirmError),
 	#[error("reboot failed: {:?}", .0)]
 	RebootFailed(String),
 	#[error("user cancelled operation")]
 	Cancelled,
+	#[error("no built update to resume from")]
+	NothingToResume,
+	#[error("generation {} does not exist", .0)]
+	NoSuchGeneration(u32),
+}
+
+impl From<BuildError> for UpgradeError {
+	fn from(e: BuildError) -> Self {
+		match e {
+			BuildError::Cancelled => Self::Cancelled,
+			e => Self::BuildError(e),
+		}
+	}
+}
+
+impl From<UpdateError> for UpgradeError {
+	fn from(e: UpdateError) -> Self {
+		match e {
+			UpdateError::Cancelled => Self::Cancelled,
+			e => Self::UpdateError(e),
+		}
+	}
+}
+
+impl From<VerifyError> for UpgradeError {
+	fn from(e: VerifyError) -> Self {
+		match e {
+			VerifyError::CommandFailed(CommandError::Cancelled) => Self::Cancelled,
+			e => Self::VerificationFailed(e),
+		}
+	}
 }
 
 impl UpgradeError {
-	pub fn map_switch_io_error(e: io::Error) -> UpgradeError {
-		Self::SwitchFailed(Some(e))
+	pub fn map_profile_error(e: CommandError) -> UpgradeError {
+		match e {
+			CommandError::Cancelled => Self::Cancelled,
+			e => Self::SwitchFailed(e),
+		}
+	}
+
+	/// The failed command that caused the error, if any.
+	pub fn command_failure(&self) -> Option<&CommandFailure> {
+		match self {
+			Self::BuildError(BuildError::NixCommandFailed(f))
+				| Self::UpdateError(UpdateError::NixCommandFailed(f))
+				| Self::UpdateError(UpdateError::GitError(GitError::CommandFailed(CommandError::Failed(f))))
+				| Self::VerificationFailed(VerifyError::CommandFailed(CommandError::Failed(f)))
+				| Self::SwitchFailed(CommandError::Failed(f))
+				| Self::RolledBack(HealthError::CommandFailed(CommandError::Failed(f))) => Some(f),
+			_ => None,
+		}
+	}
+
+	/// Exit status of the failed command or health check that caused the error, if any.
+	pub fn exit_status(&self) -> Option<ExitStatus> {
+		match self {
+			Self::RolledBack(HealthError::CheckFailed(_, status)) => Some(*status),
+			e => e.command_failure().map(|f| f.status),
+		}
 	}
 
 	pub fn map_reboot_failed(e: impl std::error::Error) -> UpgradeError {
@@ -0,0 +1,152 @@
+use crate::errors::{CommandError, GitError};
+use crate::nix::command::{piped_command, run_command, output_stderr_as_debug};
+use nix::unistd::{Gid, Uid, User};
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use tokio::process::Command;
+use tokio_util::sync::CancellationToken;
+
+/// Author and committer of commits made by the daemon.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Identity {
+	pub name: String,
+	pub email: String,
+}
+
+/// How changes are recorded in git.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct CommitOptions {
+	/// identity to commit as, None uses the repository owner's git config
+	pub identity: Option<Identity>,
+	/// add a Signed-off-by trailer
+	pub signoff: bool,
+	/// remote to push the commit to
+	pub push: Option<String>,
+}
+
+/// A git checkout that the daemon changes on behalf of the user owning it.
+///
+/// git runs as that user so that files in .git keep their owner.
+pub struct Repository {
+	dir: PathBuf,
+	owner: User,
+}
+
+impl Repository {
+	pub fn open_as_owner(dir: &Path) -> Result<Self, GitError> {
+		let uid = dir.metadata()?.uid();
+		let owner = User::from_uid(Uid::from_raw(uid))
+			.map_err(|e| GitError::NoOwner(uid, e.to_string()))?
+			.ok_or(GitError::NoOwner(uid, "no such user".to_string()))?;
+		Ok(Self { dir: dir.into(), owner })
+	}
+
+	fn git(&self) -> Command {
+		let mut cmd = piped_command("git");
+		cmd.arg("-C").arg(&self.dir)
+			.uid(self.owner.uid.as_raw())
+			.gid(Gid::as_raw(self.owner.gid))
+			.env("HOME", &self.owner.dir);
+		cmd
+	}
+
+	async fn run(&self, args: &[&str], cancel: &CancellationToken) -> Result<Vec<u8>, GitError> {
+		let mut cmd = self.git();
+		cmd.args(args);
+		Ok(run_command(cmd, cancel, output_stderr_as_debug).await?)
+	}
+
+	fn path_arg(file: &Path) -> Result<&str, GitError> {
+		file.to_str().ok_or(GitError::InvalidPath(file.into()))
+	}
+
+	/// Content of `file` in the last commit, None if it is not tracked.
+	pub async fn committed(&self, file: &Path, cancel: &CancellationToken) -> Result<Option<String>, GitError> {
+		let relative = file.strip_prefix(&self.dir).unwrap_or(file);
+		let spec = format!("HEAD:./{}", Self::path_arg(relative)?);
+		match self.run(&["show", &spec], cancel).await {
+			Ok(content) => Ok(Some(String::from_utf8_lossy(&content).into_owned())),
+			Err(GitError::CommandFailed(CommandError::Failed(_))) => Ok(None),
+			Err(e) => Err(e),
+		}
+	}
+
+	/// Commits the changes to `file` with `message`, returns false if `file` did not change.
+	pub async fn commit(&self, file: &Path, message: &str, options: &CommitOptions, cancel: &CancellationToken)
+			-> Result<bool, GitError> {
+		let file = Self::path_arg(file)?;
+		self.run(&["add", "--", file], cancel).await?;
+		// exits with 0 if nothing is staged
+		match self.run(&["diff", "--cached", "--quiet", "--", file], cancel).await {
+			Ok(_) => return Ok(false),
+			Err(GitError::CommandFailed(CommandError::Failed(_))) => (),
+			Err(e) => return Err(e),
+		}
+
+		let mut cmd = self.git();
+		if let Some(id) = &options.identity {
+			cmd.env("GIT_AUTHOR_NAME", &id.name)
+				.env("GIT_AUTHOR_EMAIL", &id.email)
+				.env("GIT_COMMITTER_NAME", &id.name)
+				.env("GIT_COMMITTER_EMAIL", &id.email);
+		}
+		cmd.args(["commit", "--message", message]);
+		if options.signoff {
+			cmd.arg("--signoff");
+		}
+		cmd.args(["--", file]);
+		run_command(cmd, cancel, output_stderr_as_debug).await?;
+
+		if let Some(remote) = &options.push {
+			self.run(&["push", remote, "HEAD"], cancel).await?;
+		}
+		Ok(true)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use mktemp::Temp;
+	use std::fs;
+
+	fn git(dir: &Path, args: &[&str]) -> String {
+		let out = std::process::Command::new("git").arg("-C").arg(dir).args(args)
+			.env("GIT_AUTHOR_NAME", "test").env("GIT_AUTHOR_EMAIL", "test@example.org")
+			.env("GIT_COMMITTER_NAME", "test").env("GIT_COMMITTER_EMAIL", "test@example.org")
+			.output().unwrap();
+		assert!(out.status.success(), "git {:?}: {}", args, String::from_utf8_lossy(&out.stderr));
+		String::from_utf8(out.stdout).unwrap()
+	}
+
+	#[tokio::test]
+	async fn commit_and_push() {
+		let remote = Temp::new_dir().unwrap();
+		let checkout = Temp::new_dir().unwrap();
+		git(&remote, &["init", "--bare", "--quiet"]);
+		git(&checkout, &["init", "--quiet"]);
+		git(&checkout, &["remote", "add", "origin", remote.to_str().unwrap()]);
+		let lock = checkout.join("flake.lock");
+		fs::write(&lock, "old").unwrap();
+		git(&checkout, &["add", "flake.lock"]);
+		git(&checkout, &["commit", "--quiet", "--message", "init"]);
+
+		let cancel = CancellationToken::new();
+		let repo = Repository::open_as_owner(&checkout).unwrap();
+		assert_eq!(repo.committed(&lock, &cancel).await.unwrap().as_deref(), Some("old"));
+		assert_eq!(repo.committed(&checkout.join("flake.nix"), &cancel).await.unwrap(), None);
+
+		let options = CommitOptions {
+			identity: Some(Identity { name: "Updater".to_string(), email: "updater@example.org".to_string() }),
+			signoff: true,
+			push: Some("origin".to_string()),
+		};
+		assert!(! repo.commit(&lock, "unchanged", &options, &cancel).await.unwrap());
+		fs::write(&lock, "new").unwrap();
+		assert!(repo.commit(&lock, "flake.lock: update", &options, &cancel).await.unwrap());
+
+		let log = git(&remote, &["log", "-1", "--format=%an <%ae>%n%B"]);
+		assert!(log.starts_with("Updater <updater@example.org>\nflake.lock: update"));
+		assert!(log.contains("Signed-off-by: Updater <updater@example.org>"));
+	}
+}
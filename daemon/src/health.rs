@@ -0,0 +1,133 @@
+use crate::errors::{CommandError, HealthError};
+use crate::nix::command::{piped_command, run_command, output_stderr_as_debug};
+use std::time::Duration;
+use tokio::time::Instant;
+use tokio_util::sync::CancellationToken;
+
+/// pause between two attempts of a failing check
+const RETRY_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Checks that a system is working after it was switched to.
+#[derive(Debug, Clone, PartialEq)]
+pub struct HealthChecks {
+	/// systemd units that must be active
+	pub units: Vec<String>,
+	/// program and arguments that must exit with 0
+	pub command: Option<Vec<String>>,
+	/// fail if more units failed than before the switch
+	pub failed_units: bool,
+	/// host that must answer a ping
+	pub reachable: Option<String>,
+	/// how long the checks may keep failing after the switch, e.g. while units start
+	pub timeout: Duration,
+}
+
+impl HealthChecks {
+	/// Not cancellable, the checks decide whether the system is rolled back.
+	async fn run(program: &str, args: &[&str]) -> Result<Vec<u8>, CommandError> {
+		let mut cmd = piped_command(program);
+		cmd.args(args);
+		run_command(cmd, &CancellationToken::new(), output_stderr_as_debug).await
+	}
+
+	/// Number of failed systemd units.
+	async fn failed_unit_count() -> Result<u32, HealthError> {
+		let out = Self::run("systemctl", &["show", "--property=NFailedUnits", "--value"]).await?;
+		let out = String::from_utf8_lossy(&out);
+		out.trim().parse().map_err(|_| HealthError::UnexpectedOutput(out.into_owned()))
+	}
+
+	/// State to compare with after the switch, to be taken before switching.
+	pub async fn baseline(&self) -> Option<u32> {
+		if ! self.failed_units {
+			return None;
+		}
+		match Self::failed_unit_count().await {
+			Ok(n) => Some(n),
+			Err(e) => {
+				log::warn!("could not count failed units, not comparing them: {}", e);
+				None
+			},
+		}
+	}
+
+	/// Runs every check once, `failed_before` is the [`Self::baseline`] taken before the switch.
+	async fn check(&self, failed_before: Option<u32>) -> Result<(), HealthError> {
+		for unit in &self.units {
+			match Self::run("systemctl", &["is-active", "--quiet", unit]).await {
+				Ok(_) => (),
+				Err(CommandError::Failed(_)) => return Err(HealthError::UnitInactive(unit.clone())),
+				Err(e) => return Err(e.into()),
+			}
+		}
+		if let Some(failed_before) = failed_before {
+			let failed = Self::failed_unit_count().await?;
+			if failed > failed_before {
+				return Err(HealthError::UnitsFailed(failed_before, failed));
+			}
+		}
+		if let Some(host) = &self.reachable {
+			match Self::run("ping", &["-c", "1", "-W", "2", host]).await {
+				Ok(_) => (),
+				Err(CommandError::Failed(_)) => return Err(HealthError::Unreachable(host.clone())),
+				Err(e) => return Err(e.into()),
+			}
+		}
+		if let Some(command) = &self.command {
+			let args: Vec<&str> = command[1..].iter().map(String::as_str).collect();
+			match Self::run(&command[0], &args).await {
+				Ok(_) => (),
+				Err(CommandError::Failed(f)) => return Err(HealthError::CheckFailed(command.join(" "), f.status)),
+				Err(e) => return Err(e.into()),
+			}
+		}
+		Ok(())
+	}
+
+	/// Repeats the checks until they all pass, returns the last failure once `timeout` is over.
+	/// A check still running at that point is stopped and counts as failed.
+	pub async fn wait_healthy(&self, failed_before: Option<u32>) -> Result<(), HealthError> {
+		let deadline = Instant::now() + self.timeout;
+		loop {
+			let err = match tokio::time::timeout_at(deadline, self.check(failed_before)).await {
+				Ok(Ok(())) => return Ok(()),
+				Ok(Err(e)) => e,
+				Err(_) => return Err(HealthError::TimedOut(self.timeout.as_secs())),
+			};
+			if Instant::now() + RETRY_INTERVAL > deadline {
+				return Err(err);
+			}
+			log::debug!("health check failed, retrying: {}", err);
+			tokio::time::sleep(RETRY_INTERVAL).await;
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn command(args: &[&str]) -> HealthChecks {
+		HealthChecks {
+			units: Vec::new(),
+			command: Some(args.iter().map(|a| a.to_string()).collect()),
+			failed_units: false,
+			reachable: None,
+			timeout: Duration::from_secs(1),
+		}
+	}
+
+	#[tokio::test]
+	async fn check_command() {
+		assert!(command(&["true"]).wait_healthy(None).await.is_ok());
+		assert!(matches!(command(&["sh", "-c", "exit 3"]).wait_healthy(None).await,
+			Err(HealthError::CheckFailed(c, s)) if c == "sh -c exit 3" && s.code() == Some(3)));
+	}
+
+	#[tokio::test]
+	async fn hanging_check_times_out() {
+		let started = Instant::now();
+		assert!(matches!(command(&["sleep", "30"]).wait_healthy(None).await, Err(HealthError::TimedOut(1))));
+		assert!(started.elapsed() < Duration::from_secs(5));
+	}
+}
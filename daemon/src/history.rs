@@ -0,0 +1,158 @@
+use crate::daemon::RunTo;
+use crate::nix::lock::InputChange;
+use crate::nix::store::StorePath;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+/// What started an upgrade run.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Trigger {
+	Schedule,
+	/// a D-Bus client, e.g. the frontend
+	User,
+	/// the daemon's command line client
+	Cli,
+}
+
+impl Trigger {
+	pub fn to_str(self) -> &'static str {
+		match self {
+			Trigger::Schedule => "schedule",
+			Trigger::User => "user",
+			Trigger::Cli => "cli",
+		}
+	}
+}
+
+/// One upgrade run, a line of the history file.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RunRecord {
+	/// id of the run's log, see [`crate::runlog::RunLogs`]
+	pub id: String,
+	/// seconds since the epoch
+	pub started: i64,
+	/// seconds since the epoch, 0 while the run is in progress
+	pub finished: i64,
+	pub trigger: Trigger,
+	pub target: RunTo,
+	/// UpdateState the run ended in, e.g. ready or error, processing while it is in progress
+	pub state: String,
+	/// last ProcessState the run reached
+	pub stage: Option<String>,
+	/// system before the run
+	pub old_system: Option<StorePath>,
+	/// system the run built or switched to
+	pub new_system: Option<StorePath>,
+	pub input_changes: Vec<InputChange>,
+	/// why the run failed
+	pub error: Option<String>,
+}
+
+impl RunRecord {
+	pub fn new(id: String, started: i64, trigger: Trigger, target: RunTo) -> Self {
+		Self {
+			id,
+			started,
+			finished: 0,
+			trigger,
+			target,
+			state: "processing".to_string(),
+			stage: None,
+			old_system: None,
+			new_system: None,
+			input_changes: Vec::new(),
+			error: None,
+		}
+	}
+}
+
+/// Past upgrade runs, kept as JSON lines in the state directory.
+///
+/// A run is written when it starts and again when it ends, the last line of a run replaces
+/// the earlier ones. A run that never ended, e.g. because the machine rebooted, keeps `finished` 0.
+pub struct History {
+	file: PathBuf,
+}
+
+impl History {
+	const FILE: &'static str = "history.jsonl";
+
+	pub fn new(state_dir: &Path) -> Self {
+		Self { file: state_dir.join(Self::FILE) }
+	}
+
+	pub fn append(&self, record: &RunRecord) -> io::Result<()> {
+		if let Some(dir) = self.file.parent() {
+			fs::create_dir_all(dir)?;
+		}
+		let mut line = serde_json::to_string(record)?;
+		line.push('\n');
+		OpenOptions::new().append(true).create(true).open(&self.file)?
+			.write_all(line.as_bytes())
+	}
+
+	/// The last `limit` runs, or all if `limit` is 0, newest first.
+	/// Lines that can not be parsed, e.g. of a run interrupted while writing, are skipped.
+	pub fn read(&self, limit: usize) -> io::Result<Vec<RunRecord>> {
+		let text = match fs::read_to_string(&self.file) {
+			Ok(text) => text,
+			Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+			Err(e) => return Err(e),
+		};
+		let mut seen = HashSet::new();
+		let records = text.lines().rev()
+			.filter(|l| ! l.trim().is_empty())
+			.filter_map(|l| serde_json::from_str(l)
+				.map_err(|e| log::warn!("skipping line of {}: {}", self.file.display(), e))
+				.ok())
+			.filter(|r: &RunRecord| seen.insert(r.id.clone()));
+		Ok(if limit == 0 { records.collect() } else { records.take(limit).collect() })
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::nix::lock::LockedInput;
+	use mktemp::Temp;
+
+	#[test]
+	fn append_and_read() {
+		let dir = Temp::new_dir().unwrap();
+		let history = History::new(&dir);
+		assert_eq!(history.read(0).unwrap(), vec![]);
+
+		let mut first = RunRecord::new("20240312-040000".to_string(), 1710212400, Trigger::Schedule, RunTo::Switch);
+		first.finished = 1710213000;
+		first.state = "up_to_date".to_string();
+		first.stage = Some("switching".to_string());
+		first.old_system = Some("/nix/store/rnxji3jf6fb0nx2v0svdqpj9ml53gyq1-nixos-system-flink".parse().unwrap());
+		first.new_system = Some("/nix/store/rnxji3jf6fb0nx2v0svdqpj9ml53gyq2-nixos-system-flink".parse().unwrap());
+		first.input_changes = vec![InputChange {
+			name: "nixpkgs".to_string(),
+			old: None,
+			new: Some(LockedInput {
+				rev: Some("a3f2b1c8e7d6c5b4a3f2b1c8e7d6c5b4a3f2b1c8".to_string()),
+				last_modified: Some(1710864000),
+				url: "github:NixOS/nixpkgs/a3f2b1c8e7d6c5b4a3f2b1c8e7d6c5b4a3f2b1c8".to_string(),
+			}),
+		}];
+		history.append(&first).unwrap();
+		// e.g. cut off by a crash
+		fs::write(dir.join(History::FILE), fs::read_to_string(dir.join(History::FILE)).unwrap() + "{\"id\":\n").unwrap();
+		let mut second = RunRecord::new("20240313-040000".to_string(), 1710298800, Trigger::Cli, RunTo::Build);
+		history.append(&second).unwrap();
+		assert_eq!(history.read(1).unwrap(), vec![second.clone()]);
+		second.finished = 1710299400;
+		second.state = "error".to_string();
+		second.error = Some("build failed".to_string());
+		history.append(&second).unwrap();
+
+		assert_eq!(history.read(0).unwrap(), vec![second.clone(), first]);
+		assert_eq!(history.read(1).unwrap(), vec![second]);
+	}
+}
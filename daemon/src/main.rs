@@ -1,13 +1,22 @@
 mod client;
 mod dbus_daemon;
+pub mod config;
 pub mod errors;
 pub mod args;
 pub mod consts;
 pub mod daemon;
 pub mod nix;
+pub mod scheduler;
+pub mod git;
+pub mod health;
+pub mod trial;
+pub mod polkit;
+pub mod runlog;
+pub mod history;
 
 use log::debug;
 use args::{Args, Command};
+use nixos_updater_interface::NixOSUpdater;
 
 fn setup_logging(verbosity: u8) {
 	stderrlog::new()
@@ -20,12 +29,26 @@ fn setup_logging(verbosity: u8) {
 
 fn handle_client_commandline(args: &Args) -> anyhow::Result<()> {
 	let client = client::Client::new()?;
-	let res = match args.command {
+	match args.command {
 		Command::Status => client.print_status(),
-		Command::BuildUpdate => client.build_update(),
-		Command::Daemon | Command::DaemonDebug => unreachable!(),
-	};
-	Ok(res?)
+		Command::BuildUpdate => client.start(|p| p.build_update()),
+		Command::Check => client.start(|p| p.check()),
+		Command::Build => client.start(|p| p.build()),
+		Command::Switch => client.start(|p| p.switch()),
+		Command::SetBoot => client.start(|p| p.set_boot()),
+		Command::Reboot => client.start(|p| p.reboot()),
+		Command::ApplyUpdate { ref target } => client.apply_update(target),
+		Command::Diff => client.print_change_summary(),
+		Command::Generations => client.print_generations(),
+		Command::Rollback { generation, ref mode } => client.rollback(generation, mode),
+		Command::Cancel => client.cancel(),
+		Command::Logs => client.print_runs(),
+		Command::Log { ref run_id, follow } => client.print_run_log(run_id.as_deref(), follow),
+		Command::BuildLog { ref drv } => client.print_derivation_log(drv),
+		Command::History { limit } => client.print_history(limit),
+		Command::ReloadConfig => client.reload_config(),
+		Command::Daemon { .. } | Command::DaemonDebug { .. } => unreachable!(),
+	}
 }
 
 fn main() -> anyhow::Result<()> {
@@ -35,18 +58,18 @@ fn main() -> anyhow::Result<()> {
 	debug!("Arguments: {:?}", args);
 
 	match args.command {
-		Command::Daemon =>
+		Command::Daemon { ref config } =>
 			tokio::runtime::Builder::new_multi_thread()
 				.enable_all()
 				.build()
 				.unwrap()
-				.block_on(dbus_daemon::main()),
-		Command::DaemonDebug =>
+				.block_on(dbus_daemon::main(config)),
+		Command::DaemonDebug { ref config } =>
 			tokio::runtime::Builder::new_multi_thread()
 				.enable_all()
 				.build()
 				.unwrap()
-				.block_on(daemon::debug_main()),
+				.block_on(daemon::debug_main(config)),
 		_ => handle_client_commandline(&args),
 	}
 }
@@ -0,0 +1,153 @@
+use crate::errors::*;
+use super::{Buildable, Updateable};
+use super::*;
+use super::command::*;
+use super::plan::{BuildPlan, DryRun};
+use super::lock::{InputChange, InputUpdate, LockedInput};
+use std::fs;
+use std::io;
+
+/// A classic NixOS configuration built from a channel of the root user.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChannelConfig {
+	/// channel name as in `nix-channel --list`, e.g. nixos
+	pub name: String,
+	/// the system configuration, usually /etc/nixos/configuration.nix
+	pub nixos_config: PathBuf,
+	/// directory holding the channels, [`Self::CHANNELS_PATH`]
+	pub channels: PathBuf,
+}
+
+impl ChannelConfig {
+	pub const CHANNELS_PATH: &'static str = "/nix/var/nix/profiles/per-user/root/channels";
+	pub const DEFAULT_NIXOS_CONFIG: &'static str = "/etc/nixos/configuration.nix";
+
+	pub fn new(name: &str, nixos_config: &Path) -> Self {
+		Self {
+			name: name.to_string(),
+			nixos_config: nixos_config.into(),
+			channels: Self::CHANNELS_PATH.into(),
+		}
+	}
+
+	fn channel_path(&self) -> PathBuf {
+		self.channels.join(&self.name)
+	}
+
+	/// nixpkgs commit the channel is at, None if the channel does not exist yet
+	fn revision(&self) -> Result<Option<String>, io::Error> {
+		match fs::read_to_string(self.channel_path().join(".git-revision")) {
+			Ok(r) => Ok(Some(r.trim().to_string())),
+			Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+			Err(e) => Err(e),
+		}
+	}
+
+	/// How the channel moved since it was at revision `old`.
+	fn changes_since(&self, old: Option<String>) -> Result<InputUpdate, io::Error> {
+		let new = self.revision()?;
+		log::debug!("channel {} at revision {:?}, was {:?}", self.name, new, old);
+		if old == new {
+			return Ok(InputUpdate::default());
+		}
+		let locked = |rev| LockedInput {
+			rev: Some(rev),
+			last_modified: None,
+			url: format!("channel:{}", self.name),
+		};
+		Ok(InputUpdate {
+			changes: vec![InputChange {
+				name: self.name.clone(),
+				old: old.map(locked),
+				new: new.map(locked),
+			}],
+			held: Vec::new(),
+		})
+	}
+
+	/// `nix build` arguments selecting the system of this configuration
+	fn installable_args(&self) -> Vec<String> {
+		vec![
+			"-I".to_string(), format!("nixpkgs={}", self.channel_path().display()),
+			"-I".to_string(), format!("nixos-config={}", self.nixos_config.display()),
+			"--file".to_string(), "<nixpkgs/nixos>".to_string(),
+			"system".to_string(),
+		]
+	}
+}
+
+#[async_trait]
+impl Buildable for ChannelConfig {
+	async fn build(&self, ctx: &CommandContext) -> Result<BuildOutput, BuildError> {
+		let wd = Temp::new_dir()?;
+		let mut cmd = nix_command();
+		cmd.current_dir(wd.as_path())
+			.arg("build")
+			.args(self.installable_args());
+		run_nix(cmd, ctx, |_| ()).await?;
+
+		BuildOutput::from_temp(wd)
+	}
+
+	async fn dry_build(&self, ctx: &CommandContext) -> Result<DryRun, BuildError> {
+		let wd = Temp::new_dir()?;
+		let mut cmd = nix_command();
+		cmd.current_dir(wd.as_path())
+			.args(["build", "--json", "--dry-run"])
+			.args(self.installable_args());
+		let mut messages = Vec::new();
+		let stdout = run_nix(cmd, ctx, |m| messages.push(m.to_string())).await?;
+
+		Ok(DryRun {
+			path: DrvResultInfo::parse_dry_run(&stdout)?,
+			plan: BuildPlan::parse(messages.iter().map(String::as_str)),
+		})
+	}
+}
+
+#[async_trait]
+impl Updateable for ChannelConfig {
+	async fn update(&self, ctx: &CommandContext) -> Result<InputUpdate, UpdateError> {
+		let old = self.revision()?;
+		let mut cmd = nix_channel_command();
+		cmd.args(["--update", &self.name]);
+		run_logged(cmd, ctx, output_stderr_as_debug).await?;
+		Ok(self.changes_since(old)?)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn channel_installable() {
+		let cc = ChannelConfig::new("nixos", Path::new(ChannelConfig::DEFAULT_NIXOS_CONFIG));
+		assert_eq!(cc.installable_args().join(" "), "-I nixpkgs=/nix/var/nix/profiles/per-user/root/channels/nixos \
+			-I nixos-config=/etc/nixos/configuration.nix --file <nixpkgs/nixos> system");
+	}
+
+	#[test]
+	fn channel_revision_changes() {
+		let dir = Temp::new_dir().unwrap();
+		let mut cc = ChannelConfig::new("nixos", Path::new(ChannelConfig::DEFAULT_NIXOS_CONFIG));
+		cc.channels = dir.to_path_buf();
+		assert_eq!(cc.revision().unwrap(), None);
+		assert_eq!(cc.changes_since(None).unwrap(), InputUpdate::default());
+
+		fs::create_dir(dir.join("nixos")).unwrap();
+		fs::write(dir.join("nixos/.git-revision"), "51063ed4f2343a59fdeebb279bb81d87d453942b\n").unwrap();
+		let old = cc.revision().unwrap();
+		assert_eq!(old.as_deref(), Some("51063ed4f2343a59fdeebb279bb81d87d453942b"));
+		assert_eq!(cc.changes_since(old.clone()).unwrap(), InputUpdate::default());
+
+		fs::write(dir.join("nixos/.git-revision"), "a3f2b1c8e7d6c5b4a3f2b1c8e7d6c5b4a3f2b1c8").unwrap();
+		let update = cc.changes_since(old).unwrap();
+		let locked = |rev: &str| Some(LockedInput { rev: Some(rev.to_string()), last_modified: None, url: "channel:nixos".to_string() });
+		assert_eq!(update.changes, vec![InputChange {
+			name: "nixos".to_string(),
+			old: locked("51063ed4f2343a59fdeebb279bb81d87d453942b"),
+			new: locked("a3f2b1c8e7d6c5b4a3f2b1c8e7d6c5b4a3f2b1c8"),
+		}]);
+	}
+}
@@ -1,30 +1,245 @@
-use crate::errors::*;
+use crate::errors::{BuildError, CommandError, CommandFailure};
 use super::store::StorePath;
-use std::process::{Command, Stdio, ChildStderr};
-use std::io::{Read, BufRead, BufReader, Lines};
-use std::collections::HashMap;
-use serde_with::{serde_as, DisplayFromStr};
+use super::progress::{LogEvent, Progress, ProgressParser};
+use crate::runlog::RunLog;
+use std::process::Stdio;
+use std::sync::Arc;
+use std::time::Duration;
+use std::collections::{HashMap, VecDeque};
+use serde_with::serde_as;
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, BufReader};
+use tokio::process::Command;
+use tokio::sync::watch;
+use tokio_util::sync::CancellationToken;
+use nix::sys::signal::{killpg, Signal};
+use nix::unistd::Pid;
 
-pub fn nix_command() -> Command {
-	let mut cmd = Command::new("nix");
+/// how long a cancelled command may take to exit after SIGTERM before it is killed
+const TERMINATE_TIMEOUT: Duration = Duration::from_secs(5);
+/// lines of stderr kept to explain why a command failed
+const STDERR_TAIL: usize = 20;
+
+pub fn piped_command(program: &str) -> Command {
+	let mut cmd = Command::new(program);
 	cmd.stdin(Stdio::null())
 		.stderr(Stdio::piped())
 		.stdout(Stdio::piped())
-		.args(["--extra-experimental-features", "nix-command flakes",
+		// own process group so that cancelling also reaches the builders nix spawns
+		.process_group(0)
+		.kill_on_drop(true);
+	cmd
+}
+
+pub fn nix_command() -> Command {
+	let mut cmd = piped_command("nix");
+	cmd.args(["--extra-experimental-features", "nix-command flakes",
+				"--log-format", "internal-json",
 				"-vv",]);
 	cmd
 }
 
-pub fn output_stderr_as_debug(stderr: &mut ChildStderr) {
-	let stderr = read_to_lines(stderr);
+pub fn nix_store_command() -> Command {
+	piped_command("nix-store")
+}
+
+pub fn nix_channel_command() -> Command {
+	piped_command("nix-channel")
+}
+
+pub fn output_stderr_as_debug(line: &str) {
+	log::debug!("{}", line);
+}
+
+/// Removes terminal escape sequences, e.g. the colors in nix's messages.
+pub(crate) fn strip_ansi(line: &str) -> String {
+	let mut out = String::with_capacity(line.len());
+	let mut chars = line.chars();
+	while let Some(c) = chars.next() {
+		if c != '\x1b' {
+			out.push(c);
+			continue;
+		}
+		if chars.next() == Some('[') {
+			// parameters until the final byte in @..~
+			for c in chars.by_ref() {
+				if ('@'..='~').contains(&c) {
+					break;
+				}
+			}
+		}
+	}
+	out
+}
+
+/// The last [`STDERR_TAIL`] lines of a command's stderr.
+#[derive(Default)]
+struct StderrTail(VecDeque<String>);
+
+impl StderrTail {
+	fn push(&mut self, text: &str) {
+		for line in text.lines() {
+			if self.0.len() == STDERR_TAIL {
+				self.0.pop_front();
+			}
+			self.0.push_back(strip_ansi(line));
+		}
+	}
+
+	fn into_lines(self) -> Vec<String> {
+		self.0.into()
+	}
+}
+
+/// Shared by the nix commands of one upgrade process.
+#[derive(Clone)]
+pub struct CommandContext {
+	/// stops the running command, see [`run_command`]
+	pub cancel: CancellationToken,
+	progress: Arc<watch::Sender<Progress>>,
+	/// log of the upgrade run the commands belong to, if it is kept
+	log: Option<Arc<RunLog>>,
+}
+
+impl Default for CommandContext {
+	fn default() -> Self {
+		Self {
+			cancel: CancellationToken::new(),
+			progress: Arc::new(watch::channel(Progress::default()).0),
+			log: None,
+		}
+	}
+}
+
+impl CommandContext {
+	pub fn new() -> Self {
+		Self::default()
+	}
+
+	/// Progress of the nix command currently running, reset whenever a new one starts.
+	pub fn subscribe_progress(&self) -> watch::Receiver<Progress> {
+		self.progress.subscribe()
+	}
+
+	pub fn set_log(&mut self, log: Arc<RunLog>) {
+		self.log = Some(log);
+	}
+
+	/// Writes `text` to the run log, if there is one.
+	pub fn log(&self, text: &str) {
+		if let Some(log) = &self.log {
+			log.write(text);
+		}
+	}
+
+	fn log_command(&self, cmd: &Command) {
+		self.log(&format!("$ {:?}", cmd.as_std()));
+	}
+}
+
+async fn terminate(child: &mut tokio::process::Child) {
+	let Some(pid) = child.id() else {
+		// already reaped
+		return;
+	};
+	let group = Pid::from_raw(pid as i32);
+	if let Err(e) = killpg(group, Signal::SIGTERM) {
+		log::warn!("could not terminate process group {}: {}", group, e);
+	}
+	if tokio::time::timeout(TERMINATE_TIMEOUT, child.wait()).await.is_err() {
+		log::warn!("process group {} did not exit, killing it", group);
+		let _ = killpg(group, Signal::SIGKILL);
+		let _ = child.wait().await;
+	}
+}
+
+/// Runs `cmd` to completion and returns its stdout, every line written to stderr is passed to `on_stderr`.
+///
+/// When `cancel` is triggered the command's process group is terminated.
+pub async fn run_command<F>(mut cmd: Command, cancel: &CancellationToken, mut on_stderr: F)
+		-> Result<Vec<u8>, CommandError>
+		where F: FnMut(&str) + Send {
+	let mut child = cmd.spawn()?;
+	let mut stdout = child.stdout.take().unwrap();
+	let mut stderr = BufReader::new(child.stderr.take().unwrap()).lines();
+	let mut tail = StderrTail::default();
 
-	for line in stderr.flatten() {
-		log::debug!("{}", line);
+	let read_stdout = async {
+		let mut buf = Vec::new();
+		stdout.read_to_end(&mut buf).await.map(|_| buf)
+	};
+	let read_stderr = async {
+		while let Some(line) = stderr.next_line().await? {
+			on_stderr(&line);
+			tail.push(&line);
+		}
+		Ok::<(), std::io::Error>(())
+	};
+	let finished = async {
+		let (out, err) = tokio::join!(read_stdout, read_stderr);
+		err?;
+		let out = out?;
+		Ok::<_, std::io::Error>((out, child.wait().await?))
+	};
+
+	let finished = tokio::select! {
+		res = finished => Some(res?),
+		_ = cancel.cancelled() => None,
+	};
+	let Some((out, status)) = finished else {
+		terminate(&mut child).await;
+		return Err(CommandError::Cancelled);
+	};
+	if ! status.success() {
+		return Err(CommandError::Failed(CommandFailure { status, stderr: tail.into_lines() }));
 	}
+	Ok(out)
 }
 
-pub fn read_to_lines<T: Read>(o: &mut T) -> Lines<BufReader<&mut T>> {
-	BufReader::new(o).lines()
+/// Runs `cmd` like [`run_command`], cancelled by `ctx` and with its stderr written to the run log.
+pub async fn run_logged<F>(cmd: Command, ctx: &CommandContext, mut on_stderr: F)
+		-> Result<Vec<u8>, CommandError>
+		where F: FnMut(&str) + Send {
+	ctx.log_command(&cmd);
+	run_command(cmd, &ctx.cancel, |line| {
+		ctx.log(line);
+		on_stderr(line);
+	}).await
+}
+
+/// Runs a command created by [`nix_command`] and publishes its progress to `ctx`.
+///
+/// Messages nix logs for humans are passed to `on_message`, build output is logged at debug level.
+/// Both are written to the run log. If the command fails, the messages and build output it logged last are kept in the error
+/// instead of the raw JSON lines.
+pub async fn run_nix<F>(cmd: Command, ctx: &CommandContext, mut on_message: F)
+		-> Result<Vec<u8>, CommandError>
+		where F: FnMut(&str) + Send {
+	let mut parser = ProgressParser::default();
+	let mut tail = StderrTail::default();
+	ctx.progress.send_replace(Progress::default());
+	ctx.log_command(&cmd);
+	let result = run_command(cmd, &ctx.cancel, |line| match parser.feed(line) {
+		LogEvent::Message(msg) => {
+			output_stderr_as_debug(&msg);
+			let msg = strip_ansi(&msg);
+			ctx.log(&msg);
+			tail.push(&msg);
+			on_message(&msg);
+		},
+		LogEvent::BuildLog(line) => {
+			output_stderr_as_debug(&line);
+			ctx.log(&line);
+			tail.push(&line);
+		},
+		LogEvent::ProgressChanged => {
+			ctx.progress.send_replace(parser.progress().clone());
+		},
+		LogEvent::None => (),
+	}).await;
+	match result {
+		Err(CommandError::Failed(f)) => Err(CommandError::Failed(CommandFailure { stderr: tail.into_lines(), ..f })),
+		r => r,
+	}
 }
 
 #[serde_as]
@@ -35,6 +250,23 @@ pub struct DrvResultInfo {
 	pub outputs: HashMap<String, StorePath>,
 }
 
+impl DrvResultInfo {
+	/// Output path of the single derivation `nix build --json --dry-run` printed.
+	pub fn parse_dry_run(stdout: &[u8]) -> Result<StorePath, BuildError> {
+		let vod: Vec<DrvResultInfo> = serde_json::from_slice(stdout)
+			.map_err(BuildError::ParsingNixBuildJSONFailed)?;
+
+		if vod.len() != 1 {
+			 return Err(BuildError::DryRunProducedUnexpected(format!("{} derivations", vod.len())));
+		}
+		let os = &vod[0].outputs;
+
+		os.get("out").cloned().ok_or(
+			BuildError::DryRunProducedUnexpected(
+				 format!("no output 'out', {} instead", serde_json::to_string(os).unwrap())))
+	}
+}
+
 #[cfg(test)]
 mod tests {
 	 use super::*;
@@ -44,4 +276,49 @@ mod tests {
 		  let v: Vec<DrvResultInfo> = serde_json::from_str(data).unwrap();
 		  println!("{:?}", v);
 	 }
+
+	 #[tokio::test]
+	 async fn run_command_output() {
+		  let mut cmd = piped_command("sh");
+		  cmd.args(["-c", "echo out; echo err >&2"]);
+		  let mut lines = Vec::new();
+		  let out = run_command(cmd, &CancellationToken::new(), |l| lines.push(l.to_string())).await.unwrap();
+		  assert_eq!(out, b"out\n");
+		  assert_eq!(lines, vec!["err"]);
+
+		  let mut cmd = piped_command("sh");
+		  cmd.args(["-c", "for i in $(seq 30); do echo $i >&2; done; exit 2"]);
+		  let Err(CommandError::Failed(f)) = run_command(cmd, &CancellationToken::new(), |_| ()).await else {
+				panic!("command did not fail");
+		  };
+		  assert_eq!(f.status.code(), Some(2));
+		  assert_eq!(f.stderr.len(), STDERR_TAIL);
+		  assert_eq!(f.stderr.last().unwrap(), "30");
+	 }
+
+	 #[test]
+	 fn failed_derivation() {
+		  let mut tail = StderrTail::default();
+		  tail.push("hello> make: *** [Makefile:12: all] Error 1");
+		  tail.push("\x1b[31;1merror:\x1b[0m builder for '\x1b[35;1m/nix/store/k6qyppd2y8yamyx7vrq3zd9vac5hgc5n-hello-2.12.1.drv\x1b[0m' \
+				failed with exit code 2;\n       last 1 log lines:\n       > make: *** [Makefile:12: all] Error 1");
+		  let f = CommandFailure { status: std::process::ExitStatus::default(), stderr: tail.into_lines() };
+		  assert_eq!(f.stderr.len(), 4);
+		  assert_eq!(f.failed_derivation(), Some("/nix/store/k6qyppd2y8yamyx7vrq3zd9vac5hgc5n-hello-2.12.1.drv"));
+	 }
+
+	 #[tokio::test]
+	 async fn cancel_command() {
+		  let mut cmd = piped_command("sh");
+		  cmd.args(["-c", "sleep 30 & wait"]);
+		  let cancel = CancellationToken::new();
+		  let canceller = cancel.clone();
+		  tokio::spawn(async move {
+				tokio::time::sleep(Duration::from_millis(100)).await;
+				canceller.cancel();
+		  });
+		  let started = std::time::Instant::now();
+		  assert!(matches!(run_command(cmd, &cancel, |_| ()).await, Err(CommandError::Cancelled)));
+		  assert!(started.elapsed() < TERMINATE_TIMEOUT);
+	 }
 }
@@ -0,0 +1,146 @@
+use crate::errors::DiffError;
+use super::command::*;
+use super::store::StorePath;
+
+/// marks a package missing on one side of `nix store diff-closures`
+const ABSENT: &str = "∅";
+/// stands for an empty version string in `nix store diff-closures`
+const EMPTY_VERSION: &str = "ε";
+
+/// Change of one package between two closures.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PackageChange {
+	pub name: String,
+	/// versions in the old closure, empty if the package was added
+	pub old_versions: Vec<String>,
+	/// versions in the new closure, empty if the package was removed
+	pub new_versions: Vec<String>,
+	/// change of the package's size in bytes
+	pub size_delta: i64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ChangeKind {
+	Added,
+	Removed,
+	VersionChanged,
+	/// same versions, only the size changed
+	Rebuilt,
+}
+
+impl PackageChange {
+	pub fn kind(&self) -> ChangeKind {
+		match (self.old_versions.is_empty(), self.new_versions.is_empty()) {
+			(true, false) => ChangeKind::Added,
+			(false, true) => ChangeKind::Removed,
+			_ if self.old_versions != self.new_versions => ChangeKind::VersionChanged,
+			_ => ChangeKind::Rebuilt,
+		}
+	}
+}
+
+/// Package level difference between two closures, see [`diff_closures`].
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct ClosureDiff {
+	pub changes: Vec<PackageChange>,
+}
+
+impl ClosureDiff {
+	/// change of the closure size in bytes, as far as diff-closures reports it
+	pub fn size_delta(&self) -> i64 {
+		self.changes.iter().map(|c| c.size_delta).sum()
+	}
+
+	pub fn parse(output: &str) -> Result<Self, DiffError> {
+		let changes = output.lines()
+			.map(strip_ansi)
+			.filter(|l| ! l.trim().is_empty())
+			.map(|l| PackageChange::parse(&l).ok_or(DiffError::UnexpectedOutput(l)))
+			.collect::<Result<_, _>>()?;
+		Ok(Self { changes })
+	}
+}
+
+/// "+1234.5 KiB" -> 1263616
+fn parse_size(s: &str) -> Option<i64> {
+	let kib: f64 = s.strip_suffix(" KiB")?.parse().ok()?;
+	Some((kib * 1024.0).round() as i64)
+}
+
+fn parse_versions(s: &str) -> Vec<String> {
+	match s.trim() {
+		ABSENT => Vec::new(),
+		s => s.split(", ")
+			.map(|v| if v == EMPTY_VERSION { String::new() } else { v.to_string() })
+			.collect(),
+	}
+}
+
+impl PackageChange {
+	/// Parses lines like "hello: 2.12 → 2.12.1, +12.5 KiB" or "systemd: +8.0 KiB".
+	fn parse(line: &str) -> Option<Self> {
+		let (name, rest) = line.split_once(": ")?;
+
+		let (versions, size_delta) = match rest.rsplit_once(", ") {
+			Some((v, size)) if size.starts_with(['+', '-']) => (Some(v), parse_size(size)?),
+			_ if rest.starts_with(['+', '-']) => (None, parse_size(rest)?),
+			_ => (Some(rest), 0),
+		};
+		let (old_versions, new_versions) = match versions {
+			Some(v) => {
+				let (old, new) = v.split_once(" → ")?;
+				(parse_versions(old), parse_versions(new))
+			},
+			None => (Vec::new(), Vec::new()),
+		};
+
+		Some(Self {
+			name: name.trim().to_string(),
+			old_versions,
+			new_versions,
+			size_delta,
+		})
+	}
+}
+
+/// Compares the closures of `from` and `to` like `nix store diff-closures`.
+pub async fn diff_closures(from: &StorePath, to: &StorePath, ctx: &CommandContext) -> Result<ClosureDiff, DiffError> {
+	let mut cmd = nix_command();
+	cmd.args(["store", "diff-closures"])
+		.arg(from.as_path())
+		.arg(to.as_path());
+	let stdout = run_nix(cmd, ctx, |_| ()).await?;
+	ClosureDiff::parse(&String::from_utf8_lossy(&stdout))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn parse_diff_closures() {
+		let diff = ClosureDiff::parse("\
+			firefox: 123.0 → 124.0.1, +1536.0 KiB\n\
+			hello: ∅ → 2.12.1, +200.5 KiB\n\
+			\x1b[31;1mnano\x1b[0m: 7.2 → ∅, -1024.0 KiB\n\
+			python3: 3.11.6, 3.12.0 → 3.11.7, 3.12.1\n\
+			systemd: +8.0 KiB\n\
+			source: ε → ∅\n").unwrap();
+
+		assert_eq!(diff.changes[0], PackageChange {
+			name: "firefox".to_string(),
+			old_versions: vec!["123.0".to_string()],
+			new_versions: vec!["124.0.1".to_string()],
+			size_delta: 1536 * 1024,
+		});
+		assert_eq!(diff.changes[1].kind(), ChangeKind::Added);
+		assert_eq!(diff.changes[2].name, "nano");
+		assert_eq!(diff.changes[2].kind(), ChangeKind::Removed);
+		assert_eq!(diff.changes[3].new_versions, vec!["3.11.7", "3.12.1"]);
+		assert_eq!(diff.changes[4].kind(), ChangeKind::Rebuilt);
+		assert_eq!(diff.changes[5].old_versions, vec![""]);
+		assert_eq!(diff.size_delta(), (1536 + 200) * 1024 + 512 - 1024 * 1024 + 8 * 1024);
+
+		assert!(matches!(ClosureDiff::parse("garbage"), Err(DiffError::UnexpectedOutput(_))));
+	}
+}
@@ -2,97 +2,287 @@ use crate::errors::*;
 use super::{Buildable, Updateable};
 use super::*;
 use super::command::*;
+use super::plan::{BuildPlan, DryRun};
+use super::lock::{FlakeLock, InputChange, InputUpdate};
+use crate::consts;
+use crate::git::{CommitOptions, Repository};
+use std::collections::BTreeMap;
+use std::os::unix::fs::{MetadataExt, PermissionsExt};
+use std::{fs, io};
 
+/// Which flake inputs an update may move.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct InputPolicy {
+	/// inputs that are updated, None means all inputs
+	pub update: Option<Vec<String>>,
+	/// inputs that stay at their locked revision
+	pub pinned: Vec<String>,
+	/// inputs locked to the newest revision of another flake reference, e.g. a release branch
+	pub follow: BTreeMap<String, String>,
+}
+
+/// How an update treats the flake.lock of a local flake.
+#[derive(Debug, Clone, Copy, Default, PartialEq, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LockMode {
+	/// the update rewrites the flake.lock right away
+	#[default]
+	InPlace,
+	/// the new lock is kept in the daemon's state directory and only written
+	/// back to the flake once the update is applied
+	WorkDir,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct LockPolicy {
+	pub mode: LockMode,
+	/// commit the flake.lock to the flake's git repository once the update is applied
+	pub commit: Option<CommitOptions>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub struct FlakeConfig {
 	pub url: String,
 	pub attribute: String,
+	pub inputs: InputPolicy,
+	pub lock: LockPolicy,
+	/// lock file used in [`LockMode::WorkDir`]
+	pub work_lock: PathBuf,
+	/// copy of the work lock the build kept for a later process was made with
+	pub kept_lock: PathBuf,
 }
 
 impl FlakeConfig {
 	pub fn new(url: &str, attr: &str) -> Self {
 		Self {
 			url: url.to_string(),
-			attribute: attr.to_string()
+			attribute: attr.to_string(),
+			inputs: InputPolicy::default(),
+			lock: LockPolicy::default(),
+			work_lock: Path::new(consts::STATE_DIR).join("flake.lock"),
+			kept_lock: Path::new(consts::STATE_DIR).join("result.lock"),
 		}
 	}
 
 	pub fn from_url_and_config_name(url: &str, config_name: &str) -> Self {
-		Self {
-			url: url.to_string(),
-			attribute: format!("nixosConfigurations.\"{config_name}\".config.system.build.toplevel"),
+		Self::new(url, &format!("nixosConfigurations.\"{config_name}\".config.system.build.toplevel"))
+	}
+
+	/// Inputs out of `locked` that the policy updates and those it holds back.
+	fn select_inputs(&self, locked: &[String]) -> (Vec<String>, Vec<String>) {
+		let wanted = |name: &String| ! self.inputs.pinned.contains(name)
+			&& ! self.inputs.follow.contains_key(name)
+			&& self.inputs.update.as_ref().is_none_or(|u| u.contains(name));
+		let mut update: Vec<String> = match &self.inputs.update {
+			Some(names) => names.iter().filter(|n| wanted(n)).cloned().collect(),
+			None => locked.iter().filter(|n| wanted(n)).cloned().collect(),
+		};
+		update.sort();
+		let held = locked.iter()
+			.filter(|n| ! wanted(n) && ! self.inputs.follow.contains_key(*n))
+			.cloned()
+			.collect();
+		(update, held)
+	}
+
+	/// `nix flake lock` arguments updating `update` and the followed inputs
+	fn lock_args(&self, update: &[String]) -> Vec<String> {
+		let mut args = vec!["flake".to_string(), "lock".to_string(), self.url.clone()];
+		for name in update {
+			args.extend(["--update-input".to_string(), name.clone()]);
+		}
+		for (name, reference) in &self.inputs.follow {
+			args.extend(["--override-input".to_string(), name.clone(), reference.clone()]);
+		}
+		args
+	}
+
+	/// arguments making nix evaluate the flake with the lock in the work directory
+	fn reference_lock_args(&self) -> Vec<String> {
+		if self.lock.mode != LockMode::WorkDir || ! self.work_lock.exists() {
+			return Vec::new();
 		}
+		vec!["--reference-lock-file".to_string(), self.work_lock.display().to_string()]
+	}
+
+	/// Starts the work lock from the flake's current lock, returns the arguments
+	/// making an update write to it.
+	fn prepare_work_lock(&self, lock_file: Option<&Path>) -> Result<Vec<String>, UpdateError> {
+		if let Some(dir) = self.work_lock.parent() {
+			fs::create_dir_all(dir)?;
+		}
+		match lock_file.filter(|l| l.exists()) {
+			Some(l) => { fs::copy(l, &self.work_lock)?; },
+			None => match fs::remove_file(&self.work_lock) {
+				Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e.into()),
+				_ => (),
+			},
+		}
+		let mut args = self.reference_lock_args();
+		args.extend(["--output-lock-file".to_string(), self.work_lock.display().to_string()]);
+		Ok(args)
+	}
+
+	/// Replaces `lock_file` with `new_lock`, keeping owner and permissions of the flake's lock.
+	///
+	/// Returns false if they did not differ.
+	fn write_back(&self, lock_file: &Path, new_lock: &Path) -> Result<bool, UpdateError> {
+		let new = fs::read(new_lock)?;
+		let reference = match fs::read(lock_file) {
+			Ok(old) if old == new => return Ok(false),
+			Ok(_) => lock_file.metadata()?,
+			Err(e) if e.kind() == io::ErrorKind::NotFound =>
+				lock_file.parent().unwrap_or(Path::new("/")).metadata()?,
+			Err(e) => return Err(e.into()),
+		};
+
+		let tmp = lock_file.with_extension("lock.nixos-updater");
+		fs::write(&tmp, new)?;
+		std::os::unix::fs::chown(&tmp, Some(reference.uid()), Some(reference.gid()))?;
+		let mode = if reference.is_dir() { 0o644 } else { reference.permissions().mode() };
+		fs::set_permissions(&tmp, fs::Permissions::from_mode(mode))?;
+		fs::rename(&tmp, lock_file)?;
+		log::info!("wrote updated lock file to {}", lock_file.display());
+		Ok(true)
+	}
+
+	/// e.g. "flake.lock: update nixpkgs" followed by a line per changed input
+	fn commit_message(changes: &[InputChange]) -> String {
+		let names: Vec<&str> = changes.iter().map(|c| c.name.as_str()).collect();
+		let mut message = match names.as_slice() {
+			[] => "flake.lock: update".to_string(),
+			names => format!("flake.lock: update {}", names.join(", ")),
+		};
+		if ! changes.is_empty() {
+			message.push('\n');
+		}
+		for c in changes {
+			message += &format!("\n{}", c);
+		}
+		message
 	}
 
 	pub fn get_installable(&self) -> String {
 		format!("{}#{}", &self.url, &self.attribute)
 	}
+
+	/// flake.lock of a flake in a local directory, None for remote flakes
+	fn lock_file(&self) -> Option<PathBuf> {
+		let path = ["path:", "git+file://"].iter()
+			.find_map(|p| self.url.strip_prefix(p))
+			.unwrap_or(&self.url);
+		let path = Path::new(path.split('?').next().unwrap_or_default());
+		path.is_absolute().then(|| path.join("flake.lock"))
+	}
 }
 
+#[async_trait]
 impl Buildable for FlakeConfig {
-	fn build(&self) -> Result<BuildOutput, BuildError> {
+	async fn build(&self, ctx: &CommandContext) -> Result<BuildOutput, BuildError> {
 		let wd = Temp::new_dir()?;
 		let installable = self.get_installable();
-		let mut child = nix_command()
-			.current_dir(&wd.as_path())
+		let mut cmd = nix_command();
+		cmd.current_dir(wd.as_path())
 			.args(["build", &installable])
-			.spawn()?;
-		
-		output_stderr_as_debug(&mut child.stderr.take().unwrap());
-		if ! child.wait()?.success() {
-			Err(BuildError::NixCommandFailed)?;
-		}
+			.args(self.reference_lock_args());
+		run_nix(cmd, ctx, |_| ()).await?;
 
-		Ok(BuildOutput::from_temp(wd)?)
+		BuildOutput::from_temp(wd)
 	}
 
-	fn dry_build(&self) -> Result<StorePath, BuildError> {
+	async fn dry_build(&self, ctx: &CommandContext) -> Result<DryRun, BuildError> {
 		let wd = Temp::new_dir()?;
 		let installable = self.get_installable();
-		let mut child = nix_command()
-			.current_dir(&wd.as_path())
+		let mut cmd = nix_command();
+		cmd.current_dir(wd.as_path())
 			.args(["build", "--json", "--dry-run", &installable])
-			.spawn()?;
-		
-		output_stderr_as_debug(&mut child.stderr.take().unwrap());
-		if ! child.wait()?.success() {
-			Err(BuildError::NixCommandFailed)?;
-		}
+			.args(self.reference_lock_args());
+		let mut messages = Vec::new();
+		let stdout = run_nix(cmd, ctx, |m| messages.push(m.to_string())).await?;
 
-		let mut bnd = child.stdout.take().unwrap();
-		let vod: Vec<DrvResultInfo> = serde_json::from_reader(BufReader::new(bnd))
-			.map_err(BuildError::ParsingNixBuildJSONFailed)?;
-
-		if vod.len() != 1 {
-			 return Err(BuildError::DryRunProducedUnexpected(format!("{} derivations", vod.len())));
-		}
-		let os = &vod[0].outputs;
-
-		os.get("out").ok_or(
-			BuildError::DryRunProducedUnexpected(
-				 format!("no output 'out', {} instead", serde_json::to_string(os).unwrap())))
+		Ok(DryRun {
+			path: DrvResultInfo::parse_dry_run(&stdout)?,
+			plan: BuildPlan::parse(messages.iter().map(String::as_str)),
+		})
 	}
 }
 
+#[async_trait]
 impl Updateable for FlakeConfig {
-	fn update(&self) -> Result<bool, UpdateError> {
-		let mut child = nix_command()
-			.args(["flake", "update", &self.url])
-			.spawn()?;
-		
-		let mut bind = child.stderr.take().unwrap();
-		let stderr = read_to_lines(&mut bind);
-		let mut has_update = false;
-		for line in stderr.flatten() {
-			log::debug!("{}", line);
-			if line.contains("updating lock file") {
-				has_update = true;
+	async fn update(&self, ctx: &CommandContext) -> Result<InputUpdate, UpdateError> {
+		let lock_file = self.lock_file();
+		let old = lock_file.as_deref().map(FlakeLock::read).transpose()?;
+		let work_lock_args = match self.lock.mode {
+			LockMode::InPlace => Vec::new(),
+			LockMode::WorkDir => self.prepare_work_lock(lock_file.as_deref())?,
+		};
+
+		let mut cmd = nix_command();
+		let mut held = Vec::new();
+		if self.inputs == InputPolicy::default() {
+			cmd.args(["flake", "update", &self.url]);
+		} else {
+			let locked: Vec<String> = old.iter().flat_map(FlakeLock::input_names).cloned().collect();
+			let update;
+			(update, held) = self.select_inputs(&locked);
+			if update.is_empty() && self.inputs.follow.is_empty() {
+				log::info!("update policy holds back all inputs of {}", self.url);
+				return Ok(InputUpdate { changes: Vec::new(), held });
 			}
+			cmd.args(self.lock_args(&update));
+		}
+		cmd.args(work_lock_args);
+		run_nix(cmd, ctx, |_| ()).await?;
+
+		let (Some(lock_file), Some(old)) = (lock_file, old) else {
+			log::warn!("can not tell which inputs of {} changed, it is not a local flake", self.url);
+			return Ok(InputUpdate { changes: Vec::new(), held });
+		};
+		let new = match self.lock.mode {
+			LockMode::InPlace => FlakeLock::read(&lock_file)?,
+			LockMode::WorkDir => FlakeLock::read(&self.work_lock)?,
+		};
+		let changes = FlakeLock::changes(&old, &new);
+		Ok(InputUpdate { changes, held })
+	}
+
+	fn keep(&self) -> Result<(), UpdateError> {
+		if self.lock.mode != LockMode::WorkDir {
+			return Ok(());
 		}
-		if ! child.wait()?.success() {
-			Err(UpdateError::NixCommandFailed)?;
+		if self.work_lock.exists() {
+			fs::copy(&self.work_lock, &self.kept_lock)?;
+			return Ok(());
 		}
+		match fs::remove_file(&self.kept_lock) {
+			Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e.into()),
+			_ => Ok(()),
+		}
+	}
 
-		Ok(has_update)
+	async fn accept(&self, ctx: &CommandContext, kept: bool) -> Result<(), UpdateError> {
+		let Some(lock_file) = self.lock_file() else {
+			return Ok(());
+		};
+		// the work lock may belong to a later check than the kept build
+		let new_lock = if kept { &self.kept_lock } else { &self.work_lock };
+		if self.lock.mode == LockMode::WorkDir && (! new_lock.exists() || ! self.write_back(&lock_file, new_lock)?) {
+			return Ok(());
+		}
+		let Some(options) = &self.lock.commit else {
+			return Ok(());
+		};
+		let dir = lock_file.parent().unwrap_or(Path::new("/"));
+		let repo = Repository::open_as_owner(dir)?;
+		let committed = repo.committed(&lock_file, &ctx.cancel).await?
+			.map(|text| FlakeLock::parse(&text))
+			.transpose()?
+			.unwrap_or_default();
+		let changes = FlakeLock::changes(&committed, &FlakeLock::read(&lock_file)?);
+		if repo.commit(&lock_file, &Self::commit_message(&changes), options, &ctx.cancel).await? {
+			log::info!("committed {}", lock_file.display());
+		}
+		Ok(())
 	}
 }
 
@@ -101,10 +291,93 @@ mod tests {
 	 use super::*;
 
 	 #[test]
-	 fn dry_build_something() {
-		  let fc = FlakeConfig::new("nixpkgs", "hello");
-		  println!("{:?}", fc.dry_build());
+	 fn local_lock_file() {
+		  let lock = |url| FlakeConfig::new(url, "hello").lock_file();
+		  assert_eq!(lock("/etc/nixos"), Some(PathBuf::from("/etc/nixos/flake.lock")));
+		  assert_eq!(lock("git+file:///etc/nixos?ref=main"), Some(PathBuf::from("/etc/nixos/flake.lock")));
+		  assert_eq!(lock("github:NixOS/nixpkgs"), None);
 	 }
-}
 
+	 #[test]
+	 fn input_policy() {
+		  let mut fc = FlakeConfig::new("/etc/nixos", "hello");
+		  fc.inputs = InputPolicy {
+				update: Some(vec!["nixpkgs".to_string(), "home-manager".to_string(), "nixos-hardware".to_string()]),
+				pinned: vec!["nixos-hardware".to_string()],
+				follow: BTreeMap::from([("nixpkgs".to_string(), "github:NixOS/nixpkgs/nixos-24.05".to_string())]),
+		  };
+		  let locked: Vec<String> = ["home-manager", "nixos-hardware", "nixpkgs", "sops-nix"]
+				.iter().map(|s| s.to_string()).collect();
+		  let (update, held) = fc.select_inputs(&locked);
+		  assert_eq!(update, vec!["home-manager"]);
+		  assert_eq!(held, vec!["nixos-hardware", "sops-nix"]);
+		  assert_eq!(fc.lock_args(&update).join(" "), "flake lock /etc/nixos --update-input home-manager \
+				--override-input nixpkgs github:NixOS/nixpkgs/nixos-24.05");
+	 }
+
+	 #[test]
+	 fn write_back_work_lock() {
+		  let dir = Temp::new_dir().unwrap();
+		  let mut fc = FlakeConfig::new(&dir.display().to_string(), "hello");
+		  fc.lock.mode = LockMode::WorkDir;
+		  fc.work_lock = dir.join("work.lock");
+		  let lock_file = fc.lock_file().unwrap();
+		  fs::write(&lock_file, "old").unwrap();
+		  fs::set_permissions(&lock_file, fs::Permissions::from_mode(0o600)).unwrap();
+
+		  fs::write(&fc.work_lock, "old").unwrap();
+		  assert!(! fc.write_back(&lock_file, &fc.work_lock).unwrap());
+		  fs::write(&fc.work_lock, "new").unwrap();
+		  assert!(fc.write_back(&lock_file, &fc.work_lock).unwrap());
+		  assert_eq!(fs::read_to_string(&lock_file).unwrap(), "new");
+		  assert_eq!(lock_file.metadata().unwrap().permissions().mode() & 0o777, 0o600);
+	 }
+
+	 #[tokio::test]
+	 async fn accept_kept_lock() {
+		  let dir = Temp::new_dir().unwrap();
+		  let mut fc = FlakeConfig::new(&dir.display().to_string(), "hello");
+		  fc.lock.mode = LockMode::WorkDir;
+		  fc.work_lock = dir.join("work.lock");
+		  fc.kept_lock = dir.join("kept.lock");
+		  let lock_file = fc.lock_file().unwrap();
+		  fs::write(&lock_file, "old").unwrap();
 
+		  fs::write(&fc.work_lock, "built").unwrap();
+		  fc.keep().unwrap();
+		  // a later check
+		  fs::write(&fc.work_lock, "checked").unwrap();
+		  fc.accept(&CommandContext::new(), true).await.unwrap();
+		  assert_eq!(fs::read_to_string(&lock_file).unwrap(), "built");
+		  fc.accept(&CommandContext::new(), false).await.unwrap();
+		  assert_eq!(fs::read_to_string(&lock_file).unwrap(), "checked");
+
+		  fs::remove_file(&fc.work_lock).unwrap();
+		  fc.keep().unwrap();
+		  assert!(! fc.kept_lock.exists());
+	 }
+
+	 #[test]
+	 fn lock_commit_message() {
+		  use super::super::lock::LockedInput;
+		  let input = |rev: &str, t| Some(LockedInput {
+				rev: Some(rev.to_string()),
+				last_modified: Some(t),
+				url: "github:NixOS/nixpkgs".to_string(),
+		  });
+		  let changes = vec![InputChange {
+				name: "nixpkgs".to_string(),
+				old: input("51063ed4f2343a59", 1710259200),
+				new: input("a3f2b1c8e7d6c5b4", 1710864000),
+		  }];
+		  assert_eq!(FlakeConfig::commit_message(&changes),
+				"flake.lock: update nixpkgs\n\nnixpkgs: 51063ed → a3f2b1c (2024-03-12 → 2024-03-19)");
+		  assert_eq!(FlakeConfig::commit_message(&[]), "flake.lock: update");
+	 }
+
+	 #[tokio::test]
+	 async fn dry_build_something() {
+		  let fc = FlakeConfig::new("nixpkgs", "hello");
+		  println!("{:?}", fc.dry_build(&CommandContext::new()).await);
+	 }
+}
@@ -0,0 +1,104 @@
+use crate::errors::*;
+use super::*;
+use super::command::*;
+
+/// Which generations of the system profile are kept, a generation is kept if any rule applies.
+///
+/// The current and the booted generation are always kept.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct RetentionPolicy {
+	/// number of newest generations to keep
+	pub keep: Option<usize>,
+	/// keep generations created less than this many days ago
+	pub keep_days: Option<u32>,
+	/// generation numbers that are never deleted
+	pub pinned: Vec<u32>,
+	/// bytes after which the store GC stops, None to delete all garbage
+	pub max_freed: Option<u64>,
+}
+
+impl RetentionPolicy {
+	const DAY: i64 = 24 * 60 * 60;
+
+	/// Numbers of the `generations` the policy deletes, `now` is in seconds since the epoch.
+	pub fn select(&self, generations: &[Generation], booted: Option<&StorePath>, now: i64) -> Vec<u32> {
+		let mut newest: Vec<u32> = generations.iter().map(|g| g.number).collect();
+		newest.sort_unstable_by(|a, b| b.cmp(a));
+		newest.truncate(self.keep.unwrap_or(0));
+
+		generations.iter()
+			.filter(|g| ! g.current && Some(&g.path) != booted)
+			.filter(|g| ! self.pinned.contains(&g.number) && ! newest.contains(&g.number))
+			.filter(|g| self.keep_days.is_none_or(|d| now - g.created >= i64::from(d) * Self::DAY))
+			.map(|g| g.number)
+			.collect()
+	}
+
+	/// Generations of `profile` that [`Self::collect`] would delete.
+	pub fn preview(&self, profile: &Profile) -> Result<Vec<u32>, GcError> {
+		let booted = StorePath::new(Path::new(Profile::BOOTED_SYSTEM)).ok();
+		let now = chrono::Utc::now().timestamp();
+		Ok(self.select(&profile.generations()?, booted.as_ref(), now))
+	}
+
+	/// Deletes the generations of `profile` not kept by the policy and collects the garbage.
+	pub async fn collect(&self, profile: &Profile, cancel: &CancellationToken) -> Result<Vec<u32>, GcError> {
+		let deleted = self.preview(profile)?;
+		if deleted.is_empty() {
+			return Ok(deleted);
+		}
+		profile.delete_generations(&deleted, cancel).await?;
+		collect_store(self.max_freed, cancel).await?;
+		Ok(deleted)
+	}
+}
+
+/// Deletes unreachable store paths until `max_freed` bytes are freed, or all of them.
+pub async fn collect_store(max_freed: Option<u64>, cancel: &CancellationToken) -> Result<(), GcError> {
+	let mut cmd = nix_store_command();
+	cmd.arg("--gc");
+	if let Some(max) = max_freed {
+		cmd.args(["--max-freed", &max.to_string()]);
+	}
+	let out = run_command(cmd, cancel, output_stderr_as_debug).await?;
+	// e.g. "1234 store paths deleted, 567.89 MiB freed"
+	if let Some(summary) = String::from_utf8_lossy(&out).lines().last() {
+		log::info!("{}", summary);
+	}
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn generation(number: u32, days_ago: i64, current: bool) -> Generation {
+		Generation {
+			number,
+			path: format!("/nix/store/rnxji3jf6fb0nx2v0svdqpj9ml53gyq{}-nixos-system-flink", number).parse().unwrap(),
+			created: 100 * RetentionPolicy::DAY - days_ago * RetentionPolicy::DAY,
+			label: None,
+			kernel: None,
+			current,
+		}
+	}
+
+	#[test]
+	fn select_generations() {
+		let now = 100 * RetentionPolicy::DAY;
+		let generations: Vec<Generation> = [(1, 60), (2, 40), (3, 20), (4, 10), (5, 1)].into_iter()
+			.map(|(n, d)| generation(n, d, n == 4))
+			.collect();
+		let booted = generations[2].path.clone();
+
+		let keep_two = RetentionPolicy { keep: Some(2), ..Default::default() };
+		assert_eq!(keep_two.select(&generations, None, now), vec![1, 2, 3]);
+		assert_eq!(keep_two.select(&generations, Some(&booted), now), vec![1, 2]);
+
+		let recent = RetentionPolicy { keep_days: Some(30), pinned: vec![1], ..Default::default() };
+		assert_eq!(recent.select(&generations, None, now), vec![2]);
+
+		let both = RetentionPolicy { keep: Some(1), keep_days: Some(15), ..Default::default() };
+		assert_eq!(both.select(&generations, None, now), vec![1, 2, 3]);
+	}
+}
@@ -0,0 +1,206 @@
+use crate::errors::UpdateError;
+use chrono::DateTime;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap};
+use std::path::Path;
+use std::{fs, io};
+
+#[derive(Debug, Deserialize)]
+struct LockFile {
+	nodes: HashMap<String, Node>,
+	root: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct Node {
+	#[serde(default)]
+	inputs: HashMap<String, InputRef>,
+	locked: Option<Locked>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum InputRef {
+	Node(String),
+	/// input following another flake's input, e.g. ["nixpkgs"]
+	Follows(serde::de::IgnoredAny),
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Locked {
+	#[serde(rename = "type")]
+	typ: String,
+	rev: Option<String>,
+	last_modified: Option<i64>,
+	owner: Option<String>,
+	repo: Option<String>,
+	url: Option<String>,
+	path: Option<String>,
+}
+
+impl Locked {
+	fn url(&self) -> String {
+		match (self.typ.as_str(), &self.owner, &self.repo) {
+			(forge @ ("github" | "gitlab" | "sourcehut"), Some(owner), Some(repo)) => match &self.rev {
+				Some(rev) => format!("{}:{}/{}/{}", forge, owner, repo, rev),
+				None => format!("{}:{}/{}", forge, owner, repo),
+			},
+			_ => match (&self.url, &self.path) {
+				(Some(url), _) => format!("{}+{}", self.typ, url),
+				(None, Some(path)) => format!("{}:{}", self.typ, path),
+				(None, None) => self.typ.clone(),
+			},
+		}
+	}
+}
+
+/// Locked state of a direct flake input.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct LockedInput {
+	pub rev: Option<String>,
+	/// seconds since the epoch
+	pub last_modified: Option<i64>,
+	pub url: String,
+}
+
+impl LockedInput {
+	fn short_rev(&self) -> &str {
+		self.rev.as_deref().map_or("unknown", |r| &r[..r.len().min(7)])
+	}
+
+	fn date(&self) -> String {
+		self.last_modified
+			.and_then(|t| DateTime::from_timestamp(t, 0))
+			.map_or("unknown".to_string(), |d| d.format("%Y-%m-%d").to_string())
+	}
+}
+
+/// An input whose locked revision changed, `old` or `new` is None if it was added or removed.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct InputChange {
+	pub name: String,
+	pub old: Option<LockedInput>,
+	pub new: Option<LockedInput>,
+}
+
+impl std::fmt::Display for InputChange {
+	/// e.g. "nixpkgs: 51063ed → a3f2b1c (2024-03-12 → 2024-03-19)"
+	fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+		let rev = |i: &Option<LockedInput>| i.as_ref().map_or("∅", LockedInput::short_rev).to_string();
+		let date = |i: &Option<LockedInput>| i.as_ref().map_or("∅".to_string(), LockedInput::date);
+		write!(f, "{}: {} → {} ({} → {})", self.name,
+			rev(&self.old), rev(&self.new), date(&self.old), date(&self.new))
+	}
+}
+
+/// Result of updating the inputs of a configuration.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct InputUpdate {
+	pub changes: Vec<InputChange>,
+	/// inputs the update policy kept at their locked revision
+	pub held: Vec<String>,
+}
+
+/// The direct inputs of a flake as locked in its flake.lock.
+#[derive(Debug, Default, PartialEq)]
+pub struct FlakeLock {
+	inputs: BTreeMap<String, LockedInput>,
+}
+
+impl FlakeLock {
+	pub fn parse(text: &str) -> Result<Self, UpdateError> {
+		let file: LockFile = serde_json::from_str(text).map_err(UpdateError::ParsingLockFileFailed)?;
+		let Some(root) = file.nodes.get(&file.root) else {
+			return Ok(Self::default());
+		};
+		let inputs = root.inputs.iter()
+			.filter_map(|(name, r)| match r {
+				InputRef::Node(node) => Some((name, node)),
+				// follows are locked by the input they point to
+				InputRef::Follows(_) => None,
+			})
+			.filter_map(|(name, node)| {
+				let locked = file.nodes.get(node)?.locked.as_ref()?;
+				Some((name.clone(), LockedInput {
+					rev: locked.rev.clone(),
+					last_modified: locked.last_modified,
+					url: locked.url(),
+				}))
+			})
+			.collect();
+		Ok(Self { inputs })
+	}
+
+	pub fn input_names(&self) -> impl Iterator<Item = &String> {
+		self.inputs.keys()
+	}
+
+	/// Reads `path`, a missing lock file has no inputs.
+	pub fn read(path: &Path) -> Result<Self, UpdateError> {
+		match fs::read_to_string(path) {
+			Ok(text) => Self::parse(&text),
+			Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Self::default()),
+			Err(e) => Err(e.into()),
+		}
+	}
+
+	/// Inputs that differ between `old` and `new`, sorted by name.
+	pub fn changes(old: &Self, new: &Self) -> Vec<InputChange> {
+		let mut names: Vec<&String> = old.inputs.keys().chain(new.inputs.keys()).collect();
+		names.sort();
+		names.dedup();
+		names.into_iter()
+			.filter(|n| old.inputs.get(*n) != new.inputs.get(*n))
+			.map(|n| InputChange {
+				name: n.clone(),
+				old: old.inputs.get(n).cloned(),
+				new: new.inputs.get(n).cloned(),
+			})
+			.collect()
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn lock(nixpkgs_rev: &str, last_modified: i64) -> FlakeLock {
+		FlakeLock::parse(&format!(r#"{{
+			"nodes": {{
+				"home-manager": {{
+					"inputs": {{ "nixpkgs": ["nixpkgs"] }},
+					"locked": {{ "lastModified": 1710000000, "owner": "nix-community", "repo": "home-manager",
+						"rev": "0c3a1b5e2f", "type": "github" }}
+				}},
+				"nixpkgs": {{
+					"locked": {{ "lastModified": {}, "owner": "NixOS", "repo": "nixpkgs",
+						"rev": "{}", "type": "github" }}
+				}},
+				"root": {{
+					"inputs": {{ "home-manager": "home-manager", "nixpkgs": "nixpkgs" }}
+				}}
+			}},
+			"root": "root",
+			"version": 7
+		}}"#, last_modified, nixpkgs_rev)).unwrap()
+	}
+
+	#[test]
+	fn diff_lock_files() {
+		let old = lock("51063ed4f2343a59fdeebb279bb81d87d453942b", 1710259200);
+		let new = lock("a3f2b1c8e7d6c5b4a3f2b1c8e7d6c5b4a3f2b1c8", 1710864000);
+		assert_eq!(FlakeLock::changes(&old, &old), vec![]);
+
+		let changes = FlakeLock::changes(&old, &new);
+		assert_eq!(changes.len(), 1);
+		assert_eq!(changes[0].new.as_ref().unwrap().url,
+			"github:NixOS/nixpkgs/a3f2b1c8e7d6c5b4a3f2b1c8e7d6c5b4a3f2b1c8");
+		assert_eq!(changes[0].to_string(), "nixpkgs: 51063ed → a3f2b1c (2024-03-12 → 2024-03-19)");
+
+		let added = FlakeLock::changes(&FlakeLock::default(), &new);
+		assert_eq!(added.len(), 2);
+		assert_eq!(added[0].name, "home-manager");
+		assert_eq!(added[0].old, None);
+	}
+}
@@ -1,22 +1,31 @@
 pub mod store;
 pub mod flake;
+pub mod channel;
 pub mod command;
+pub mod progress;
+pub mod diff;
+pub mod lock;
+pub mod prebuilt;
+pub mod verify;
+pub mod gc;
+pub mod plan;
 
 use std::path::{Path, PathBuf};
-use std::process::{Command, Stdio, ChildStderr};
-use std::{io, fs};
-use std::io::{BufRead, BufReader};
+use std::fs;
 use mktemp::Temp;
+use async_trait::async_trait;
+use tokio_util::sync::CancellationToken;
 use crate::errors::*;
 
 use store::StorePath;
-use flake::FlakeConfig;
+use command::*;
 
 
 #[derive(Debug)]
 pub struct BuildOutput {
 	pub path: StorePath,
-	pub linkdir: Temp,
+	/// temporary directory holding the result link, None if the output is kept alive elsewhere
+	pub linkdir: Option<Temp>,
 }
 
 impl BuildOutput {
@@ -29,19 +38,71 @@ impl BuildOutput {
 	pub fn from_temp(linkdir: Temp) -> Result<Self, BuildError> {
 		Ok(Self {
 			path: Self::read_link_dir(&linkdir)?,
-			linkdir
+			linkdir: Some(linkdir),
 		})
 	}
+
+	/// Output kept alive by the GC root at `root`, see [`Self::add_root`].
+	pub fn from_root(root: &Path) -> Result<Self, BuildError> {
+		Ok(Self {
+			path: StorePath::new(root)?,
+			linkdir: None,
+		})
+	}
+
+	/// Registers `root` as a GC root pointing to this output.
+	pub async fn add_root(&self, root: &Path, cancel: &CancellationToken) -> Result<(), BuildError> {
+		let mut cmd = nix_store_command();
+		cmd.arg("--realise").arg(self.path.as_path())
+			.arg("--add-root").arg(root);
+		run_command(cmd, cancel, output_stderr_as_debug).await?;
+		Ok(())
+	}
 }
 
+#[async_trait]
 pub trait Buildable {
-	fn build(&self) -> Result<BuildOutput, BuildError>;
-	fn dry_build(&self) -> Result<StorePath, BuildError>;
+	async fn build(&self, ctx: &CommandContext) -> Result<BuildOutput, BuildError>;
+	async fn dry_build(&self, ctx: &CommandContext) -> Result<plan::DryRun, BuildError>;
 }
 
+#[async_trait]
 pub trait Updateable {
-	/// return true if the flake inputs (or channel revision) have changed
-	fn update(&self) -> Result<bool, UpdateError>;
+	/// return the flake inputs (or the channel) whose revision has changed
+	async fn update(&self, ctx: &CommandContext) -> Result<lock::InputUpdate, UpdateError>;
+
+	/// called when the build is kept for a later process to apply, saves what [`Updateable::accept`] needs then
+	fn keep(&self) -> Result<(), UpdateError> {
+		Ok(())
+	}
+
+	/// called once the update was applied to the system, `kept` if the build applied is the one
+	/// saved by [`Updateable::keep`] rather than the one just built
+	async fn accept(&self, _ctx: &CommandContext, _kept: bool) -> Result<(), UpdateError> {
+		Ok(())
+	}
+}
+
+/// Build log nix kept of the derivation `drv`, like `nix log`.
+pub async fn derivation_log(drv: &StorePath, cancel: &CancellationToken) -> Result<String, CommandError> {
+	let mut cmd = nix_command();
+	cmd.arg("log").arg(drv.as_path());
+	let out = run_command(cmd, cancel, output_stderr_as_debug).await?;
+	Ok(String::from_utf8_lossy(&out).into_owned())
+}
+
+/// A generation of a system profile, e.g. /nix/var/nix/profiles/system-42-link.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Generation {
+	pub number: u32,
+	pub path: StorePath,
+	/// seconds since the epoch
+	pub created: i64,
+	/// NixOS version, e.g. 24.05.20240312.51063ed
+	pub label: Option<String>,
+	pub kernel: Option<String>,
+	/// generation the profile points to
+	pub current: bool,
 }
 
 pub struct Profile {
@@ -49,15 +110,119 @@ pub struct Profile {
 }
 
 impl Profile {
+	pub const SYSTEM_PATH: &'static str = "/nix/var/nix/profiles/system";
+	/// system the machine was booted with
+	pub const BOOTED_SYSTEM: &'static str = "/run/booted-system";
+
 	pub fn new(p: &Path) -> Self {
 		Self { base_path: p.into() }
 	}
 
 	pub fn system() -> Self {
-		Self::new(Path::new("/nix/var/nix/profiles/system"))
+		Self::new(Path::new(Self::SYSTEM_PATH))
 	}
 
 	pub fn get_current(&self) -> Result<StorePath, StorePathError> {
-		Ok(self.base_path.as_path().try_into()?)
+		self.base_path.as_path().try_into()
+	}
+
+	/// Number of the generation link named `file_name`, e.g. 42 for system-42-link.
+	fn generation_number(&self, file_name: &str) -> Option<u32> {
+		let profile = self.base_path.file_name()?.to_str()?;
+		file_name.strip_prefix(profile)?
+			.strip_prefix('-')?
+			.strip_suffix("-link")?
+			.parse().ok()
+	}
+
+	fn read_generation(&self, number: u32, link: &Path, current: Option<u32>) -> Result<Generation, StorePathError> {
+		let path = StorePath::new(&fs::read_link(link)?)?;
+		let created = link.symlink_metadata()?.modified()?
+			.duration_since(std::time::UNIX_EPOCH).map_or(0, |d| d.as_secs() as i64);
+		let label = fs::read_to_string(path.subpath("nixos-version")).ok()
+			.map(|l| l.trim().to_string());
+		let kernel = StorePath::new(&path.subpath("kernel")).ok()
+			.and_then(|k| k.version().map(str::to_string));
+		Ok(Generation { number, path, created, label, kernel, current: current == Some(number) })
+	}
+
+	/// All generations of the profile, oldest first.
+	pub fn generations(&self) -> Result<Vec<Generation>, StorePathError> {
+		let dir = self.base_path.parent().unwrap_or(Path::new("/"));
+		let current = fs::read_link(&self.base_path).ok()
+			.and_then(|l| self.generation_number(l.file_name()?.to_str()?));
+		let mut generations = Vec::new();
+		for entry in fs::read_dir(dir)? {
+			let entry = entry?;
+			let Some(number) = entry.file_name().to_str().and_then(|n| self.generation_number(n)) else {
+				continue;
+			};
+			match self.read_generation(number, &entry.path(), current) {
+				Ok(g) => generations.push(g),
+				// e.g. a dangling link of a generation that is being deleted
+				Err(e) => log::debug!("skipping generation {}: {}", number, e),
+			}
+		}
+		generations.sort_by_key(|g| g.number);
+		Ok(generations)
+	}
+
+	pub fn generation(&self, number: u32) -> Result<Option<Generation>, StorePathError> {
+		Ok(self.generations()?.into_iter().find(|g| g.number == number))
+	}
+
+	/// Adds `system` as a new generation and points the profile to it, like nixos-rebuild does
+	/// before activating. The boot loader entries are made from the generations.
+	pub async fn set(&self, system: &StorePath, cancel: &CancellationToken) -> Result<(), CommandError> {
+		let mut cmd = piped_command("nix-env");
+		cmd.arg("--profile").arg(&self.base_path)
+			.arg("--set").arg(system.as_path());
+		run_command(cmd, cancel, output_stderr_as_debug).await?;
+		Ok(())
+	}
+
+	/// Points the profile to generation `number`, like `nix-env --switch-generation`.
+	pub async fn switch_generation(&self, number: u32, cancel: &CancellationToken) -> Result<(), CommandError> {
+		let mut cmd = piped_command("nix-env");
+		cmd.arg("--profile").arg(&self.base_path)
+			.args(["--switch-generation", &number.to_string()]);
+		run_command(cmd, cancel, output_stderr_as_debug).await?;
+		Ok(())
+	}
+
+	/// Removes the generations `numbers`, their store paths are only deleted by the next GC.
+	pub async fn delete_generations(&self, numbers: &[u32], cancel: &CancellationToken) -> Result<(), CommandError> {
+		let mut cmd = piped_command("nix-env");
+		cmd.arg("--profile").arg(&self.base_path)
+			.arg("--delete-generations")
+			.args(numbers.iter().map(u32::to_string));
+		run_command(cmd, cancel, output_stderr_as_debug).await?;
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::os::unix::fs::symlink;
+
+	#[test]
+	fn list_generations() {
+		let dir = Temp::new_dir().unwrap();
+		let system = |n: u32| format!("/nix/store/rnxji3jf6fb0nx2v0svdqpj9ml53gyq{}-nixos-system-flink", n);
+		symlink(system(1), dir.join("system-1-link")).unwrap();
+		symlink(system(2), dir.join("system-2-link")).unwrap();
+		symlink(system(10), dir.join("system-10-link")).unwrap();
+		symlink("system-2-link", dir.join("system")).unwrap();
+		symlink(system(3), dir.join("other-3-link")).unwrap();
+
+		let profile = Profile::new(&dir.join("system"));
+		let generations = profile.generations().unwrap();
+		assert_eq!(generations.iter().map(|g| g.number).collect::<Vec<_>>(), vec![1, 2, 10]);
+		assert_eq!(generations[2].path.to_string(), system(10));
+		assert!(generations[1].current);
+		assert!(! generations[0].current);
+		assert_eq!(generations[0].label, None);
+		assert_eq!(profile.generation(3).unwrap(), None);
 	}
 }
@@ -0,0 +1,187 @@
+use crate::errors::SpaceError;
+use super::store::StorePath;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// What realising a system would build and fetch, as printed by `nix build --dry-run`
+/// or `nix-store --realise --dry-run`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct BuildPlan {
+	/// derivations to build
+	pub to_build: Vec<StorePath>,
+	/// paths to download from substituters
+	pub to_fetch: Vec<StorePath>,
+	/// bytes to download
+	pub download_size: u64,
+	/// bytes the fetched paths take in the store
+	pub unpacked_size: u64,
+}
+
+#[derive(Clone, Copy)]
+enum Section {
+	Build,
+	Fetch,
+}
+
+/// `nix build --dry-run` and the output it would produce.
+#[derive(Debug)]
+pub struct DryRun {
+	pub path: StorePath,
+	pub plan: BuildPlan,
+}
+
+impl BuildPlan {
+	/// Bytes of e.g. "93.91 MiB".
+	fn parse_size(s: &str) -> Option<u64> {
+		let (number, unit) = s.trim().split_once(' ')?;
+		let factor = match unit {
+			"B" | "bytes" => 1.0,
+			"KiB" => 1024.0,
+			"MiB" => 1024.0 * 1024.0,
+			"GiB" => 1024.0 * 1024.0 * 1024.0,
+			_ => return None,
+		};
+		Some((number.parse::<f64>().ok()? * factor) as u64)
+	}
+
+	/// Download and unpacked size of e.g. "these 82 paths will be fetched (93.91 MiB download, 458.72 MiB unpacked):".
+	fn parse_sizes(header: &str) -> Option<(u64, u64)> {
+		let sizes = header.split_once('(')?.1.split_once(')')?.0;
+		let (download, unpacked) = sizes.split_once(',')?;
+		Some((Self::parse_size(download.strip_suffix(" download")?)?,
+			Self::parse_size(unpacked.trim().strip_suffix(" unpacked")?)?))
+	}
+
+	/// Feeds the messages of a dry run, a message may span several lines.
+	pub fn parse<'a>(messages: impl IntoIterator<Item = &'a str>) -> Self {
+		let mut plan = Self::default();
+		let mut section = None;
+		for line in messages.into_iter().flat_map(str::lines) {
+			if let Some(path) = line.strip_prefix("  ") {
+				let Ok(path) = path.trim().parse() else {
+					continue;
+				};
+				match section {
+					Some(Section::Build) => plan.to_build.push(path),
+					Some(Section::Fetch) => plan.to_fetch.push(path),
+					None => (),
+				}
+			} else if line.ends_with("will be built:") {
+				section = Some(Section::Build);
+			} else if line.contains("will be fetched") {
+				section = Some(Section::Fetch);
+				if let Some((download, unpacked)) = Self::parse_sizes(line) {
+					plan.download_size += download;
+					plan.unpacked_size += unpacked;
+				}
+			} else {
+				section = None;
+			}
+		}
+		plan
+	}
+
+	/// Whether the plan builds or fetches a kernel or an initrd, which end up in /boot.
+	pub fn touches_boot(&self) -> bool {
+		self.to_build.iter().chain(&self.to_fetch)
+			.any(|p| p.name().starts_with("linux-") || p.name().starts_with("initrd-"))
+	}
+}
+
+/// Bytes available to unprivileged users on the file system of `path`.
+fn available_space(path: &Path) -> nix::Result<u64> {
+	let stat = nix::sys::statvfs::statvfs(path)?;
+	#[allow(clippy::unnecessary_cast)]
+	Ok(stat.blocks_available() as u64 * stat.fragment_size() as u64)
+}
+
+/// Bytes the kernel and initrd of `system` take, None if `system` is not realised.
+fn boot_files_size(system: &Path) -> Option<u64> {
+	["kernel", "initrd"].iter()
+		.map(|f| fs::metadata(system.join(f)).ok().map(|m| m.len()))
+		.sum()
+}
+
+/// Free space a [`BuildPlan`] needs before it is started.
+#[derive(Debug, Clone, PartialEq)]
+pub struct SpaceCheck {
+	/// bytes that must stay free in the store after fetching, builds need space too
+	pub store_reserve: u64,
+	/// bytes that must stay free on /boot after copying the new kernel and initrd,
+	/// if the plan changes them
+	pub boot_reserve: u64,
+	/// collect garbage instead of failing if the store is too full
+	pub collect_garbage: bool,
+}
+
+impl SpaceCheck {
+	pub const STORE: &'static str = "/nix/store";
+	pub const BOOT: &'static str = "/boot";
+
+	fn require(path: &str, needed: u64) -> Result<(), SpaceError> {
+		let available = match available_space(Path::new(path)) {
+			Ok(a) => a,
+			Err(e) => {
+				log::warn!("could not check free space on {}: {}", path, e);
+				return Ok(());
+			},
+		};
+		if available < needed {
+			return Err(SpaceError { path: PathBuf::from(path), needed, available });
+		}
+		Ok(())
+	}
+
+	/// Checks the space for realising `plan` to build `system`, the boot loader copies its kernel
+	/// and initrd to /boot. Before `system` is realised they are assumed to be as large as
+	/// those of `current`, the current system.
+	pub fn check(&self, plan: &BuildPlan, system: &StorePath, current: Option<&StorePath>) -> Result<(), SpaceError> {
+		Self::require(Self::STORE, plan.unpacked_size + self.store_reserve)?;
+		if plan.touches_boot() {
+			let boot_files = boot_files_size(system.as_path())
+				.or_else(|| current.and_then(|c| boot_files_size(c.as_path())))
+				.unwrap_or(0);
+			Self::require(Self::BOOT, boot_files + self.boot_reserve)?;
+		}
+		Ok(())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn parse_dry_run_messages() {
+		let plan = BuildPlan::parse([
+			"these 2 derivations will be built:\n  /nix/store/k6qyppd2y8yamyx7vrq3zd9vac5hgc5n-etc.drv\n  \
+				/nix/store/rnxji3jf6fb0nx2v0svdqpj9ml53gyqh-nixos-system-flink.drv",
+			"these 3 paths will be fetched (93.91 MiB download, 458.72 MiB unpacked):",
+			"  /nix/store/0c3a1b5e2f9nx2v0svdqpj9ml53gyqh-linux-6.6.22",
+			"  /nix/store/1c3a1b5e2f9nx2v0svdqpj9ml53gyqh-linux-6.6.22-modules",
+			"  /nix/store/2c3a1b5e2f9nx2v0svdqpj9ml53gyqh-hello-2.12.1",
+			"copying path '/nix/store/2c3a1b5e2f9nx2v0svdqpj9ml53gyqh-hello-2.12.1'",
+		]);
+		assert_eq!(plan.to_build.len(), 2);
+		assert_eq!(plan.to_fetch.len(), 3);
+		assert_eq!(plan.download_size, (93.91 * 1024.0 * 1024.0) as u64);
+		assert_eq!(plan.unpacked_size, (458.72 * 1024.0 * 1024.0) as u64);
+		assert!(plan.touches_boot());
+
+		let plan = BuildPlan::parse(["this path will be fetched (0.01 MiB download, 0.05 MiB unpacked):",
+			"  /nix/store/2c3a1b5e2f9nx2v0svdqpj9ml53gyqh-hello-2.12.1"]);
+		assert_eq!(plan.to_fetch.len(), 1);
+		assert!(! plan.touches_boot());
+		assert_eq!(BuildPlan::parse([]), BuildPlan::default());
+	}
+
+	#[test]
+	fn size_of_boot_files() {
+		let dir = mktemp::Temp::new_dir().unwrap();
+		assert_eq!(boot_files_size(&dir), None);
+		fs::write(dir.join("kernel"), [0; 1000]).unwrap();
+		assert_eq!(boot_files_size(&dir), None);
+		fs::write(dir.join("initrd"), [0; 500]).unwrap();
+		assert_eq!(boot_files_size(&dir), Some(1500));
+	}
+}
@@ -0,0 +1,166 @@
+use crate::errors::*;
+use crate::consts;
+use super::{Buildable, Updateable};
+use super::*;
+use super::command::*;
+use super::plan::{BuildPlan, DryRun};
+use super::lock::{InputChange, InputUpdate, LockedInput};
+use std::{fs, io};
+
+/// Where CI publishes the store path of the system to switch to.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ClosureSource {
+	/// text file served over http(s)
+	Url(String),
+	/// local text file, e.g. on a shared file system
+	File(PathBuf),
+}
+
+/// A system built elsewhere and fetched from binary caches instead of being evaluated locally.
+#[derive(Debug, Clone, PartialEq)]
+pub struct PrebuiltConfig {
+	pub source: ClosureSource,
+	/// binary caches the closure must be available from
+	pub substituters: Vec<String>,
+	/// store path found by the last update
+	pub fetched: PathBuf,
+}
+
+impl PrebuiltConfig {
+	pub fn new(source: ClosureSource, substituters: &[String]) -> Self {
+		Self {
+			source,
+			substituters: substituters.to_vec(),
+			fetched: Path::new(consts::STATE_DIR).join("prebuilt-path"),
+		}
+	}
+
+	async fn read_source(&self, ctx: &CommandContext) -> Result<String, UpdateError> {
+		let text = match &self.source {
+			ClosureSource::File(path) => fs::read_to_string(path)?,
+			ClosureSource::Url(url) => {
+				let mut cmd = piped_command("curl");
+				cmd.args(["--fail", "--silent", "--show-error", "--location", url]);
+				let out = run_logged(cmd, ctx, output_stderr_as_debug).await?;
+				String::from_utf8_lossy(&out).into_owned()
+			},
+		};
+		Ok(text.trim().to_string())
+	}
+
+	fn last_fetched(&self) -> Result<Option<String>, io::Error> {
+		match fs::read_to_string(&self.fetched) {
+			Ok(p) => Ok(Some(p.trim().to_string())),
+			Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+			Err(e) => Err(e),
+		}
+	}
+
+	fn fetched_path(&self) -> Result<StorePath, BuildError> {
+		let path = self.last_fetched()?
+			.ok_or(BuildError::DryRunProducedUnexpected("no store path fetched yet".to_string()))?;
+		Ok(path.parse()?)
+	}
+
+	fn substituter_args(&self) -> [String; 3] {
+		["--option".to_string(), "extra-substituters".to_string(), self.substituters.join(" ")]
+	}
+
+	/// Checks that `path` can be substituted from one of the configured caches.
+	async fn verify_available(&self, path: &StorePath, ctx: &CommandContext) -> Result<(), BuildError> {
+		for substituter in &self.substituters {
+			let mut cmd = nix_command();
+			cmd.args(["path-info", "--store", substituter]).arg(path.as_path());
+			match run_nix(cmd, ctx, |_| ()).await {
+				Ok(_) => return Ok(()),
+				Err(CommandError::Failed(_)) => log::debug!("{} is not in {}", path, substituter),
+				Err(e) => return Err(e.into()),
+			}
+		}
+		Err(BuildError::NotInSubstituters(path.to_string()))
+	}
+}
+
+#[async_trait]
+impl Buildable for PrebuiltConfig {
+	async fn build(&self, ctx: &CommandContext) -> Result<BuildOutput, BuildError> {
+		let path = self.fetched_path()?;
+		self.verify_available(&path, ctx).await?;
+
+		let wd = Temp::new_dir()?;
+		let mut cmd = nix_store_command();
+		cmd.arg("--realise").arg(path.as_path())
+			.arg("--add-root").arg(wd.join("result"))
+			.args(self.substituter_args());
+		run_logged(cmd, ctx, output_stderr_as_debug).await?;
+
+		BuildOutput::from_temp(wd)
+	}
+
+	async fn dry_build(&self, ctx: &CommandContext) -> Result<DryRun, BuildError> {
+		let path = self.fetched_path()?;
+		self.verify_available(&path, ctx).await?;
+
+		let mut cmd = nix_store_command();
+		cmd.args(["--realise", "--dry-run"]).arg(path.as_path())
+			.args(self.substituter_args());
+		let mut lines = Vec::new();
+		run_logged(cmd, ctx, |l| lines.push(l.to_string())).await?;
+		Ok(DryRun { path, plan: BuildPlan::parse(lines.iter().map(String::as_str)) })
+	}
+}
+
+#[async_trait]
+impl Updateable for PrebuiltConfig {
+	async fn update(&self, ctx: &CommandContext) -> Result<InputUpdate, UpdateError> {
+		let new = self.read_source(ctx).await?;
+		// reject garbage before it is stored
+		let _: StorePath = new.parse()?;
+		let old = self.last_fetched()?;
+		if old.as_ref() == Some(&new) {
+			return Ok(InputUpdate::default());
+		}
+
+		if let Some(dir) = self.fetched.parent() {
+			fs::create_dir_all(dir)?;
+		}
+		fs::write(&self.fetched, &new)?;
+		let url = match &self.source {
+			ClosureSource::Url(url) => url.clone(),
+			ClosureSource::File(path) => format!("file://{}", path.display()),
+		};
+		let locked = |path| LockedInput { rev: Some(path), last_modified: None, url: url.clone() };
+		Ok(InputUpdate {
+			changes: vec![InputChange {
+				name: "system".to_string(),
+				old: old.map(locked),
+				new: Some(locked(new)),
+			}],
+			held: Vec::new(),
+		})
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[tokio::test]
+	async fn update_from_file() {
+		let dir = Temp::new_dir().unwrap();
+		let published = dir.join("flink");
+		let mut pc = PrebuiltConfig::new(ClosureSource::File(published.clone()), &[]);
+		pc.fetched = dir.join("fetched");
+		let ctx = CommandContext::new();
+
+		fs::write(&published, "/nix/store/rnxji3jf6fb0nx2v0svdqpj9ml53gyqh-nixos-system-flink\n").unwrap();
+		let update = pc.update(&ctx).await.unwrap();
+		assert_eq!(update.changes.len(), 1);
+		assert_eq!(update.changes[0].old, None);
+		assert_eq!(pc.update(&ctx).await.unwrap(), InputUpdate::default());
+
+		fs::write(&published, "not a store path").unwrap();
+		assert!(matches!(pc.update(&ctx).await, Err(UpdateError::StorePathError(_))));
+		assert!(matches!(pc.dry_build(&ctx).await, Err(BuildError::NotInSubstituters(_))));
+	}
+}
@@ -0,0 +1,203 @@
+use std::collections::HashMap;
+use serde::Deserialize;
+use serde_json::Value;
+
+/// Prefix of nix's `--log-format internal-json` lines
+const JSON_PREFIX: &str = "@nix ";
+
+// activity and result types from nix's libutil/logging.hh
+const ACT_FILE_TRANSFER: u64 = 101;
+const ACT_BUILDS: u64 = 104;
+const ACT_BUILD: u64 = 105;
+const RES_BUILD_LOG_LINE: u64 = 101;
+const RES_PROGRESS: u64 = 105;
+const RES_SET_EXPECTED: u64 = 106;
+
+/// How far a nix command has come.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Progress {
+	pub builds_done: u64,
+	pub builds_expected: u64,
+	pub bytes_downloaded: u64,
+	pub bytes_expected: u64,
+	/// name of the derivation that most recently started building and is still running
+	pub current_build: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "action", rename_all = "lowercase")]
+enum LogMessage {
+	Start {
+		id: u64,
+		#[serde(rename = "type")]
+		typ: u64,
+		#[serde(default)]
+		fields: Vec<Value>,
+	},
+	Stop {
+		id: u64,
+	},
+	Result {
+		id: u64,
+		#[serde(rename = "type")]
+		typ: u64,
+		#[serde(default)]
+		fields: Vec<Value>,
+	},
+	Msg {
+		msg: String,
+	},
+}
+
+/// What a log line meant for the caller.
+#[derive(Debug, PartialEq)]
+pub enum LogEvent {
+	/// a message meant for humans, including lines nix did not log as JSON
+	Message(String),
+	/// output of a builder
+	BuildLog(String),
+	ProgressChanged,
+	None,
+}
+
+#[derive(Debug, Default)]
+struct Transfer {
+	done: u64,
+	expected: u64,
+}
+
+/// Turns the lines of `--log-format internal-json` into a [`Progress`].
+#[derive(Debug, Default)]
+pub struct ProgressParser {
+	progress: Progress,
+	activity_types: HashMap<u64, u64>,
+	transfers: HashMap<u64, Transfer>,
+	/// bytes announced by SetExpected results, keyed by activity
+	announced_bytes: HashMap<u64, u64>,
+	/// running builds in the order they started
+	running_builds: Vec<(u64, String)>,
+}
+
+fn field(fields: &[Value], i: usize) -> u64 {
+	fields.get(i).and_then(Value::as_u64).unwrap_or(0)
+}
+
+/// "/nix/store/<hash>-hello-2.12.1.drv" -> "hello-2.12.1"
+fn derivation_name(drv_path: &str) -> String {
+	let base = drv_path.rsplit('/').next().unwrap_or(drv_path);
+	let base = base.strip_suffix(".drv").unwrap_or(base);
+	match base.split_once('-') {
+		Some((_hash, name)) => name.to_string(),
+		None => base.to_string(),
+	}
+}
+
+impl ProgressParser {
+	pub fn progress(&self) -> &Progress {
+		&self.progress
+	}
+
+	pub fn feed(&mut self, line: &str) -> LogEvent {
+		let Some(json) = line.strip_prefix(JSON_PREFIX) else {
+			return LogEvent::Message(line.to_string());
+		};
+		let msg: LogMessage = match serde_json::from_str(json) {
+			Ok(m) => m,
+			// nix sends more actions than we care about
+			Err(_) => return LogEvent::None,
+		};
+
+		let before = self.progress.clone();
+		match msg {
+			LogMessage::Msg { msg } => return LogEvent::Message(msg),
+			LogMessage::Start { id, typ, fields } => {
+				self.activity_types.insert(id, typ);
+				match typ {
+					ACT_BUILD => {
+						let drv = fields.first().and_then(Value::as_str).unwrap_or_default();
+						self.running_builds.push((id, derivation_name(drv)));
+					},
+					ACT_FILE_TRANSFER => {
+						self.transfers.insert(id, Transfer::default());
+					},
+					_ => (),
+				}
+			},
+			LogMessage::Stop { id } => {
+				self.running_builds.retain(|(b, _)| *b != id);
+			},
+			LogMessage::Result { typ: RES_BUILD_LOG_LINE, fields, .. } => {
+				let line = fields.first().and_then(Value::as_str).unwrap_or_default();
+				return LogEvent::BuildLog(line.to_string());
+			},
+			LogMessage::Result { id, typ: RES_PROGRESS, fields } => {
+				match self.activity_types.get(&id) {
+					Some(&ACT_BUILDS) => {
+						self.progress.builds_done = field(&fields, 0);
+						self.progress.builds_expected = field(&fields, 1);
+					},
+					Some(&ACT_FILE_TRANSFER) => {
+						let t = self.transfers.entry(id).or_default();
+						t.done = field(&fields, 0);
+						t.expected = field(&fields, 1);
+					},
+					_ => (),
+				}
+			},
+			LogMessage::Result { id, typ: RES_SET_EXPECTED, fields } => {
+				if field(&fields, 0) == ACT_FILE_TRANSFER {
+					self.announced_bytes.insert(id, field(&fields, 1));
+				}
+			},
+			LogMessage::Result { .. } => (),
+		}
+
+		self.progress.current_build = self.running_builds.last().map(|(_, name)| name.clone());
+		self.progress.bytes_downloaded = self.transfers.values().map(|t| t.done).sum();
+		let transfer_expected: u64 = self.transfers.values().map(|t| t.expected).sum();
+		let announced: u64 = self.announced_bytes.values().sum();
+		self.progress.bytes_expected = transfer_expected.max(announced);
+
+		if self.progress != before {
+			LogEvent::ProgressChanged
+		} else {
+			LogEvent::None
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn parse_build_progress() {
+		let mut p = ProgressParser::default();
+		let lines = [
+			r#"@nix {"action":"start","id":1,"level":0,"parent":0,"text":"","type":104}"#,
+			r#"@nix {"action":"result","fields":[0,2,0,0],"id":1,"type":105}"#,
+			r#"@nix {"action":"start","id":2,"level":3,"parent":1,"text":"building '/nix/store/k6qyppd2y8yamyx7vrq3zd9vac5hgc5n-hello-2.12.1.drv'","type":105,"fields":["/nix/store/k6qyppd2y8yamyx7vrq3zd9vac5hgc5n-hello-2.12.1.drv","",1,1]}"#,
+			r#"@nix {"action":"start","id":3,"level":4,"parent":0,"text":"downloading 'https://cache.nixos.org/nar/x.nar.xz'","type":101,"fields":["https://cache.nixos.org/nar/x.nar.xz"]}"#,
+			r#"@nix {"action":"result","fields":[1024,4096,0,0],"id":3,"type":105}"#,
+			r#"@nix {"action":"result","fields":[101,8192],"id":1,"type":106}"#,
+		];
+		for l in lines {
+			p.feed(l);
+		}
+		assert_eq!(p.progress(), &Progress {
+			builds_done: 0,
+			builds_expected: 2,
+			bytes_downloaded: 1024,
+			bytes_expected: 8192,
+			current_build: Some("hello-2.12.1".to_string()),
+		});
+
+		assert_eq!(p.feed(r#"@nix {"action":"result","fields":["make: done"],"id":2,"type":101}"#),
+			LogEvent::BuildLog("make: done".to_string()));
+		assert_eq!(p.feed(r#"@nix {"action":"stop","id":2}"#), LogEvent::ProgressChanged);
+		assert_eq!(p.progress().current_build, None);
+		assert_eq!(p.feed(r#"@nix {"action":"msg","level":3,"msg":"updating lock file"}"#),
+			LogEvent::Message("updating lock file".to_string()));
+		assert_eq!(p.feed("warning: Git tree is dirty"), LogEvent::Message("warning: Git tree is dirty".to_string()));
+	}
+}
@@ -3,7 +3,7 @@ use std::path::{Path, PathBuf};
 use std::fs;
 use std::str::FromStr;
 
-#[derive(Debug, PartialEq, serde_with::DeserializeFromStr, serde_with::SerializeDisplay)]
+#[derive(Debug, Clone, PartialEq, serde_with::DeserializeFromStr, serde_with::SerializeDisplay)]
 pub struct StorePath(PathBuf);
 
 impl StorePath {
@@ -13,10 +13,7 @@ impl StorePath {
 			Err(_) => PathBuf::from(p),
 		};
 		if ! can.starts_with("/nix/store/") {
-			let s = match can.to_str() {
-				Some(s) => s,
-				None => "unprintable path",
-			};
+			let s = can.to_str().unwrap_or("unprintable path");
 			Err(StorePathError::NotInStore(s.to_string()))
 		} else {
 			Ok(Self(can))
@@ -40,7 +37,7 @@ impl TryFrom<&Path> for StorePath {
 	type Error = StorePathError;
 
 	fn try_from(p: &Path) -> Result<Self, Self::Error> {
-		Self::new(p.into())
+		Self::new(p)
 	}
 }
 
@@ -69,6 +66,38 @@ impl StorePath {
 	pub fn as_path(&self) -> &Path {
 		self.0.as_path()
 	}
+
+	/// name of the store object without its hash, e.g. "hello-2.12.1" for
+	/// /nix/store/<hash>-hello-2.12.1/bin/hello
+	pub fn name(&self) -> &str {
+		let object = self.0.strip_prefix("/nix/store").ok()
+			.and_then(|p| p.components().next())
+			.and_then(|c| c.as_os_str().to_str())
+			.unwrap_or_default();
+		object.split_once('-').map_or(object, |(_hash, name)| name)
+	}
+
+	/// version part of [`Self::name`], e.g. "6.6.20" for linux-6.6.20
+	pub fn version(&self) -> Option<&str> {
+		let name = self.name();
+		name.match_indices('-')
+			.map(|(i, _)| &name[i + 1..])
+			.find(|v| v.starts_with(|c: char| c.is_ascii_digit()))
+	}
 }
 
 
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn name_and_version() {
+		let p: StorePath = "/nix/store/w2ks8fwra5bwv6sd4qaxa1l8yqn0mza1-linux-6.6.20/bzImage".parse().unwrap();
+		assert_eq!(p.name(), "linux-6.6.20");
+		assert_eq!(p.version(), Some("6.6.20"));
+		let p: StorePath = "/nix/store/a1l8yqn0mza1w2ks8fwra5bwv6sd4qax-firmware".parse().unwrap();
+		assert_eq!(p.version(), None);
+	}
+}
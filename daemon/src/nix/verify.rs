@@ -0,0 +1,83 @@
+use crate::errors::*;
+use super::command::*;
+use super::store::StorePath;
+
+/// Signatures a system closure must carry before it is switched to.
+///
+/// Locally built paths are only signed if nix has a secret key configured,
+/// so this is mostly useful together with prebuilt or substituted systems.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TrustPolicy {
+	/// keys in the format of nix.conf's trusted-public-keys, e.g. cache.nixos.org-1:6NCH…
+	pub public_keys: Vec<String>,
+	/// number of signatures by these keys every path needs
+	pub sigs_needed: u32,
+}
+
+impl TrustPolicy {
+	/// Path named in a message like "path '/nix/store/…' is untrusted".
+	fn untrusted_path(msg: &str) -> Option<&str> {
+		let rest = msg.split_once("path '")?.1;
+		let (path, rest) = rest.split_once('\'')?;
+		rest.trim_start().starts_with("is untrusted").then_some(path)
+	}
+
+	/// Outcome of `nix store verify`, which reported the paths in `untrusted` as unsigned.
+	fn outcome(result: Result<Vec<u8>, CommandError>, untrusted: Vec<String>) -> Result<(), VerifyError> {
+		match result {
+			Ok(_) => Ok(()),
+			Err(CommandError::Failed(_)) if ! untrusted.is_empty() => Err(VerifyError::Untrusted(untrusted)),
+			Err(e) => Err(e.into()),
+		}
+	}
+
+	/// Checks the signatures of every path in the closure of `path`, but not their contents.
+	pub async fn verify(&self, path: &StorePath, ctx: &CommandContext) -> Result<(), VerifyError> {
+		let mut cmd = nix_command();
+		cmd.args(["store", "verify", "--recursive", "--no-contents"])
+			.args(["--sigs-needed", &self.sigs_needed.to_string()])
+			.args(["--option", "trusted-public-keys", &self.public_keys.join(" ")])
+			.arg(path.as_path());
+
+		let mut untrusted = Vec::new();
+		let result = run_nix(cmd, ctx, |msg| {
+			if let Some(p) = Self::untrusted_path(msg) {
+				untrusted.push(p.to_string());
+			}
+		}).await;
+		Self::outcome(result, untrusted)
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn parse_untrusted_path() {
+		assert_eq!(TrustPolicy::untrusted_path(
+			"path '/nix/store/rnxji3jf6fb0nx2v0svdqpj9ml53gyqh-hello-2.12.1' is untrusted"),
+			Some("/nix/store/rnxji3jf6fb0nx2v0svdqpj9ml53gyqh-hello-2.12.1"));
+		assert_eq!(TrustPolicy::untrusted_path("1 paths checked, 1 untrusted"), None);
+		assert_eq!(TrustPolicy::untrusted_path("copying path '/nix/store/x' from 'https://cache.nixos.org'"), None);
+	}
+
+	#[test]
+	fn failure_without_untrusted_paths() {
+		use crate::errors::CommandFailure;
+		use std::os::unix::process::ExitStatusExt;
+
+		let failed = || Err(CommandError::Failed(CommandFailure {
+			status: std::process::ExitStatus::from_raw(1 << 8),
+			stderr: vec!["error: path '/nix/store/x' is not valid".to_string()],
+		}));
+		let untrusted = vec!["/nix/store/rnxji3jf6fb0nx2v0svdqpj9ml53gyqh-hello-2.12.1".to_string()];
+		assert!(matches!(TrustPolicy::outcome(failed(), untrusted.clone()), Err(VerifyError::Untrusted(u)) if u == untrusted));
+		let Err(VerifyError::CommandFailed(CommandError::Failed(f))) = TrustPolicy::outcome(failed(), Vec::new()) else {
+			panic!("failure reported as untrusted paths");
+		};
+		assert_eq!(f.status.code(), Some(1));
+		assert_eq!(f.stderr.len(), 1);
+		assert!(TrustPolicy::outcome(Ok(Vec::new()), Vec::new()).is_ok());
+	}
+}
@@ -0,0 +1,102 @@
+use crate::errors::AuthError;
+use crate::daemon::RunTo;
+use dbus::arg::{PropMap, RefArg, Variant};
+use dbus::nonblock::{Proxy, SyncConnection};
+use std::collections::HashMap;
+use std::time::Duration;
+
+/// polkit actions guarding the daemon's methods, see data/de.afuchs.NixOSUpdater.policy
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Action {
+	Check,
+	Build,
+	Switch,
+	Reboot,
+	/// reloading the config
+	Configure,
+}
+
+impl Action {
+	pub fn id(self) -> &'static str {
+		match self {
+			Action::Check => "de.afuchs.NixOSUpdater.check",
+			Action::Build => "de.afuchs.NixOSUpdater.build",
+			Action::Switch => "de.afuchs.NixOSUpdater.switch",
+			Action::Reboot => "de.afuchs.NixOSUpdater.reboot",
+			Action::Configure => "de.afuchs.NixOSUpdater.configure",
+		}
+	}
+
+	/// Action needed to run an update to `target`.
+	pub fn for_target(target: RunTo) -> Self {
+		match target {
+			RunTo::Cancel | RunTo::Check => Action::Check,
+			RunTo::Build => Action::Build,
+			RunTo::Switch | RunTo::SetBoot => Action::Switch,
+			RunTo::Reboot => Action::Reboot,
+		}
+	}
+}
+
+const AUTHORITY: &str = "org.freedesktop.PolicyKit1";
+const AUTHORITY_IFACE: &str = "org.freedesktop.PolicyKit1.Authority";
+const AUTHORITY_PATH: &str = "/org/freedesktop/PolicyKit1/Authority";
+/// long enough for the user to enter a password
+const TIMEOUT: Duration = Duration::from_secs(120);
+/// lets polkit ask the user to authenticate
+const ALLOW_USER_INTERACTION: u32 = 1;
+
+/// Asks polkit whether the peer with the unique bus name `sender` may perform `action`.
+pub async fn check_authorization(con: &SyncConnection, sender: &str, action: Action) -> Result<(), AuthError> {
+	let proxy = Proxy::new(AUTHORITY, AUTHORITY_PATH, TIMEOUT, con);
+	let mut subject_details = PropMap::new();
+	subject_details.insert("name".to_string(), Variant(Box::new(sender.to_string()) as Box<dyn RefArg>));
+	let subject = ("system-bus-name", subject_details);
+	let details: HashMap<&str, &str> = HashMap::new();
+
+	let ((authorized, _challenge, _details),): ((bool, bool, HashMap<String, String>),) = proxy
+		.method_call(AUTHORITY_IFACE, "CheckAuthorization", (subject, action.id(), details, ALLOW_USER_INTERACTION, ""))
+		.await?;
+	if ! authorized {
+		return Err(AuthError::NotAuthorized(action.id()));
+	}
+	Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::dbus_daemon::tests::TestBus;
+	use dbus::channel::{Channel, MatchingReceiver};
+	use dbus::message::MatchRule;
+	use dbus_crossroads::Crossroads;
+
+	#[tokio::test]
+	async fn ask_authority() {
+		let bus = TestBus::start();
+		let mut channel = Channel::open_private(&bus.address).unwrap();
+		channel.register().unwrap();
+		let (resource, con) = dbus_tokio::connection::from_channel::<SyncConnection>(channel).unwrap();
+		tokio::spawn(resource);
+
+		// authorizes only checking for updates
+		let mut cr = Crossroads::new();
+		let token = cr.register(AUTHORITY_IFACE, |b| {
+			b.method("CheckAuthorization", ("subject", "action_id", "details", "flags", "cancellation_id"), ("result",),
+				|_, _, (_subject, action, _details, _flags, _cancel): ((String, PropMap), String, HashMap<String, String>, u32, String)| {
+					Ok(((action == Action::Check.id(), false, HashMap::<String, String>::new()),))
+				});
+		});
+		cr.insert(AUTHORITY_PATH, &[token], ());
+		con.start_receive(MatchRule::new_method_call(), Box::new(move |msg, conn| {
+			cr.handle_message(msg, conn).unwrap();
+			true
+		}));
+		con.request_name(AUTHORITY, false, false, true).await.unwrap();
+
+		let sender = con.unique_name().to_string();
+		check_authorization(&con, &sender, Action::Check).await.unwrap();
+		assert!(matches!(check_authorization(&con, &sender, Action::Reboot).await,
+			Err(AuthError::NotAuthorized("de.afuchs.NixOSUpdater.reboot"))));
+	}
+}
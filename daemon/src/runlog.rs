@@ -0,0 +1,176 @@
+use chrono::{DateTime, Local};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+/// Log file of one upgrade run, written by the commands the run executes.
+#[derive(Debug)]
+pub struct RunLog {
+	id: String,
+	file: Mutex<File>,
+}
+
+impl RunLog {
+	pub fn id(&self) -> &str {
+		&self.id
+	}
+
+	/// Appends `text` and a line break.
+	pub fn write(&self, text: &str) {
+		let mut file = self.file.lock().unwrap();
+		if let Err(e) = writeln!(file, "{}", text) {
+			log::warn!("could not write log of run {}: {}", self.id, e);
+		}
+	}
+}
+
+/// A run whose log is kept.
+#[derive(Debug, PartialEq)]
+pub struct RunInfo {
+	pub id: String,
+	/// last write to the log in seconds since the epoch
+	pub modified: i64,
+	/// size of the log in bytes
+	pub size: u64,
+}
+
+/// Directory holding the logs of the last [`RunLogs::KEEP`] upgrade runs, named `<run-id>.log`.
+///
+/// Run ids are the local start time, so that they sort by age.
+#[derive(Debug, Clone)]
+pub struct RunLogs {
+	dir: PathBuf,
+}
+
+impl RunLogs {
+	pub const DIR: &'static str = "/var/log/nixos-updater";
+	const KEEP: usize = 20;
+	const SUFFIX: &'static str = ".log";
+
+	pub fn new(dir: &Path) -> Self {
+		Self { dir: dir.to_path_buf() }
+	}
+
+	/// Ids and log files of the kept runs, oldest first. Other files in the directory are ignored.
+	fn runs(&self) -> io::Result<Vec<(String, PathBuf)>> {
+		let entries = match fs::read_dir(&self.dir) {
+			Ok(entries) => entries,
+			Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+			Err(e) => return Err(e),
+		};
+		let mut runs = Vec::new();
+		for entry in entries {
+			let name = entry?.file_name();
+			let Some(id) = name.to_str().and_then(|n| n.strip_suffix(Self::SUFFIX)) else {
+				continue;
+			};
+			if let Some(path) = self.path(id) {
+				runs.push((id.to_string(), path));
+			}
+		}
+		runs.sort();
+		Ok(runs)
+	}
+
+	/// Log file of run `id`, None if `id` can not be a run id.
+	fn path(&self, id: &str) -> Option<PathBuf> {
+		let valid = ! id.is_empty() && id.chars().all(|c| c.is_ascii_digit() || c == '-');
+		valid.then(|| self.dir.join(format!("{}{}", id, Self::SUFFIX)))
+	}
+
+	/// Id of a run starting at `now`, the id of its log may get a suffix if another run started
+	/// within the same second.
+	pub fn run_id(now: DateTime<Local>) -> String {
+		now.format("%Y%m%d-%H%M%S").to_string()
+	}
+
+	/// Creates the log of a run starting at `now` and deletes the oldest logs beyond [`Self::KEEP`].
+	pub fn start(&self, now: DateTime<Local>) -> io::Result<RunLog> {
+		fs::create_dir_all(&self.dir)?;
+		let base = Self::run_id(now);
+		let mut id = base.clone();
+		let file = loop {
+			let path = self.path(&id).ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "invalid run id"))?;
+			match OpenOptions::new().append(true).create_new(true).open(&path) {
+				Ok(file) => break file,
+				// two runs within a second
+				Err(e) if e.kind() == io::ErrorKind::AlreadyExists => id = format!("{}-{}", base, rand::random::<u16>()),
+				Err(e) => return Err(e),
+			}
+		};
+
+		let runs = self.runs()?;
+		for (old, path) in &runs[..runs.len().saturating_sub(Self::KEEP)] {
+			if let Err(e) = fs::remove_file(path) {
+				log::warn!("could not remove log of run {}: {}", old, e);
+			}
+		}
+		Ok(RunLog { id, file: Mutex::new(file) })
+	}
+
+	/// The kept runs, newest first.
+	pub fn list(&self) -> io::Result<Vec<RunInfo>> {
+		let mut runs = Vec::new();
+		for (id, path) in self.runs()?.into_iter().rev() {
+			let meta = fs::metadata(path)?;
+			let modified = meta.modified()?.duration_since(std::time::UNIX_EPOCH).map_or(0, |d| d.as_secs() as i64);
+			runs.push(RunInfo { id, modified, size: meta.len() });
+		}
+		Ok(runs)
+	}
+
+	/// Up to `max` bytes of the log of run `id` from byte `offset` on, and the offset to continue
+	/// reading from. Reading again from the returned offset follows a log that is still written.
+	pub fn read(&self, id: &str, offset: u64, max: usize) -> io::Result<(String, u64)> {
+		let path = self.path(id).ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "invalid run id"))?;
+		let mut file = File::open(path)?;
+		file.seek(SeekFrom::Start(offset))?;
+		let mut buf = Vec::new();
+		file.take(max as u64).read_to_end(&mut buf)?;
+		// leave a character cut off at the end for the next read
+		if let Err(e) = std::str::from_utf8(&buf) {
+			if e.error_len().is_none() {
+				buf.truncate(e.valid_up_to());
+			}
+		}
+		let next = offset + buf.len() as u64;
+		Ok((String::from_utf8_lossy(&buf).into_owned(), next))
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use chrono::TimeZone;
+	use mktemp::Temp;
+
+	#[test]
+	fn rotate_and_read() {
+		let dir = Temp::new_dir().unwrap();
+		let logs = RunLogs::new(&dir);
+		// not written by the daemon
+		fs::write(dir.join("foo.log"), "").unwrap();
+		let start = Local.with_ymd_and_hms(2024, 3, 12, 4, 0, 0).unwrap();
+		for minute in 0..RunLogs::KEEP as i64 + 2 {
+			logs.start(start + chrono::Duration::minutes(minute)).unwrap();
+		}
+		let runs = logs.list().unwrap();
+		assert_eq!(runs.len(), RunLogs::KEEP);
+		assert_eq!(runs[0].id, "20240312-042100");
+		assert_eq!(runs.last().unwrap().id, "20240312-040200");
+		assert!(dir.join("foo.log").exists());
+
+		let log = logs.start(start + chrono::Duration::minutes(21)).unwrap();
+		assert_ne!(log.id(), "20240312-042100");
+		log.write("building 'hello.drv'…");
+		log.write("done");
+		let (text, next) = logs.read(log.id(), 0, 21).unwrap();
+		assert_eq!(text, "building 'hello.drv'");
+		let (text, next) = logs.read(log.id(), next, 100).unwrap();
+		assert_eq!(text, "…\ndone\n");
+		assert_eq!(logs.read(log.id(), next, 100).unwrap(), (String::new(), next));
+
+		assert!(logs.read("../../etc/passwd", 0, 100).is_err());
+	}
+}
@@ -0,0 +1,227 @@
+use crate::daemon::RunTo;
+use chrono::{DateTime, Datelike, Duration, Local, NaiveTime, TimeZone, Timelike, Weekday};
+use rand::Rng;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::{fs, io};
+
+/// Simplified systemd-style calendar expression.
+///
+/// Accepted forms are `hourly`, `daily`, `weekly` and an optional list of
+/// weekdays followed by an optional time, e.g. `daily 03:00`, `Mon,Thu 12:30` or `04:15`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Calendar {
+	/// days to run on, None means every day
+	weekdays: Option<Vec<Weekday>>,
+	/// hour to run at, None means every hour
+	hour: Option<u32>,
+	minute: u32,
+}
+
+impl FromStr for Calendar {
+	type Err = String;
+
+	fn from_str(s: &str) -> Result<Self, Self::Err> {
+		let mut tokens: Vec<&str> = s.split_whitespace().collect();
+
+		let time = match tokens.last() {
+			Some(t) if t.contains(':') => {
+				let t = NaiveTime::parse_from_str(t, "%H:%M")
+					.map_err(|_| format!("invalid time {}, expected HH:MM", t))?;
+				tokens.pop();
+				Some(t)
+			},
+			_ => None,
+		};
+		let (hour, minute) = time.map_or((0, 0), |t| (t.hour(), t.minute()));
+
+		match tokens.as_slice() {
+			[] if time.is_some() => Ok(Self { weekdays: None, hour: Some(hour), minute }),
+			["hourly"] if time.is_none() => Ok(Self { weekdays: None, hour: None, minute: 0 }),
+			["daily"] => Ok(Self { weekdays: None, hour: Some(hour), minute }),
+			["weekly"] => Ok(Self { weekdays: Some(vec![Weekday::Mon]), hour: Some(hour), minute }),
+			[days] => {
+				let weekdays = days.split(',')
+					.map(|d| d.parse::<Weekday>().map_err(|_| format!("invalid weekday {}", d)))
+					.collect::<Result<Vec<_>, _>>()?;
+				Ok(Self { weekdays: Some(weekdays), hour: Some(hour), minute })
+			},
+			_ => Err(format!("invalid calendar expression \"{}\"", s)),
+		}
+	}
+}
+
+impl Calendar {
+	/// First time strictly after `t` that matches the expression.
+	pub fn next_after<Tz: TimeZone>(&self, t: &DateTime<Tz>) -> DateTime<Tz> {
+		let tz = t.timezone();
+		let Some(hour) = self.hour else {
+			let this_hour = t.clone() - Duration::minutes(t.minute().into())
+				- Duration::seconds(t.second().into())
+				- Duration::nanoseconds(t.nanosecond().into());
+			return this_hour + Duration::hours(1);
+		};
+
+		let time = NaiveTime::from_hms_opt(hour, self.minute, 0).unwrap();
+		// a week and a day covers every weekday even if today's slot has passed,
+		// days where the time does not exist because of DST changes are skipped
+		(0..=8)
+			.map(|offset| t.date_naive() + Duration::days(offset))
+			.filter(|d| self.weekdays.as_ref().is_none_or(|w| w.contains(&d.weekday())))
+			.filter_map(|d| tz.from_local_datetime(&d.and_time(time)).earliest())
+			.find(|c| c > t)
+			.expect("calendar expression matches at least once a week")
+	}
+}
+
+#[derive(Debug, Clone)]
+pub struct Schedule {
+	pub calendar: Calendar,
+	/// upper bound of the random delay added to every run
+	pub randomized_delay: std::time::Duration,
+	pub target: RunTo,
+}
+
+/// A run of the schedule that is due and has not started yet.
+#[derive(Debug, Clone, Copy)]
+struct PlannedRun {
+	/// time the calendar expression matched
+	slot: DateTime<Local>,
+	/// random delay added to the slot, or to the time the run was planned if the slot was missed
+	delay: Duration,
+	time: DateTime<Local>,
+}
+
+/// Keeps track of when scheduled updates ran and when the next one is due.
+pub struct Scheduler {
+	state_file: PathBuf,
+	last_run: Option<DateTime<Local>>,
+	next_run: Option<PlannedRun>,
+}
+
+impl Scheduler {
+	const STATE_FILE: &'static str = "last-scheduled-run";
+
+	pub fn new(state_dir: &Path) -> Self {
+		let state_file = state_dir.join(Self::STATE_FILE);
+		let last_run = match fs::read_to_string(&state_file) {
+			Ok(s) => DateTime::parse_from_rfc3339(s.trim())
+				.map(|t| t.with_timezone(&Local))
+				.map_err(|e| log::warn!("ignoring {}: {}", state_file.display(), e))
+				.ok(),
+			Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+			Err(e) => {
+				log::warn!("could not read {}: {}", state_file.display(), e);
+				None
+			},
+		};
+		Self { state_file, last_run, next_run: None }
+	}
+
+	pub fn last_run(&self) -> Option<DateTime<Local>> {
+		self.last_run
+	}
+
+	pub fn next_run(&self) -> Option<DateTime<Local>> {
+		self.next_run.map(|r| r.time)
+	}
+
+	/// Computes the next run of `schedule`. A run that was missed since the
+	/// last one, e.g. because the machine was off, is due immediately.
+	///
+	/// A run planned before keeps its time as long as it has not run and still matches
+	/// `schedule`, so that planning again does not draw a new random delay.
+	pub fn plan(&mut self, schedule: Option<&Schedule>, now: DateTime<Local>) {
+		let Some(s) = schedule else {
+			self.next_run = None;
+			return;
+		};
+		let max_delay = Duration::seconds(s.randomized_delay.as_secs() as i64);
+		let planned = self.next_run.filter(|r| self.last_run.is_none_or(|last| last < r.slot)
+			&& s.calendar.next_after(&(r.slot - Duration::seconds(1))) == r.slot
+			&& r.delay <= max_delay);
+		self.next_run = Some(planned.unwrap_or_else(|| {
+			let slot = match self.last_run {
+				Some(last) => s.calendar.next_after(&last),
+				None => s.calendar.next_after(&now),
+			};
+			let max_delay = max_delay.num_seconds();
+			let delay = Duration::seconds(if max_delay > 0 { rand::thread_rng().gen_range(0..=max_delay) } else { 0 });
+			PlannedRun { slot, delay, time: slot.max(now) + delay }
+		}));
+	}
+
+	pub fn record_run(&mut self, t: DateTime<Local>) -> io::Result<()> {
+		self.last_run = Some(t);
+		if let Some(dir) = self.state_file.parent() {
+			fs::create_dir_all(dir)?;
+		}
+		fs::write(&self.state_file, t.to_rfc3339())
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use chrono::Utc;
+
+	fn at(s: &str) -> DateTime<Utc> {
+		DateTime::parse_from_rfc3339(s).unwrap().with_timezone(&Utc)
+	}
+
+	#[test]
+	fn parse_calendar() {
+		let daily: Calendar = "daily 03:00".parse().unwrap();
+		assert_eq!(daily, Calendar { weekdays: None, hour: Some(3), minute: 0 });
+		assert_eq!("03:00".parse::<Calendar>().unwrap(), daily);
+		assert_eq!("weekly".parse::<Calendar>().unwrap(),
+			Calendar { weekdays: Some(vec![Weekday::Mon]), hour: Some(0), minute: 0 });
+		assert_eq!("Mon,Thu 12:30".parse::<Calendar>().unwrap(),
+			Calendar { weekdays: Some(vec![Weekday::Mon, Weekday::Thu]), hour: Some(12), minute: 30 });
+		assert!("".parse::<Calendar>().is_err());
+		assert!("daily 25:00".parse::<Calendar>().is_err());
+		assert!("hourly 03:00".parse::<Calendar>().is_err());
+		assert!("sometimes".parse::<Calendar>().is_err());
+	}
+
+	#[test]
+	fn next_run_times() {
+		let daily: Calendar = "daily 03:00".parse().unwrap();
+		assert_eq!(daily.next_after(&at("2024-03-12T02:00:00Z")), at("2024-03-12T03:00:00Z"));
+		assert_eq!(daily.next_after(&at("2024-03-12T03:00:00Z")), at("2024-03-13T03:00:00Z"));
+
+		let hourly: Calendar = "hourly".parse().unwrap();
+		assert_eq!(hourly.next_after(&at("2024-03-12T23:15:10Z")), at("2024-03-13T00:00:00Z"));
+
+		// 2024-03-12 is a Tuesday
+		let weekly: Calendar = "Mon,Thu 12:30".parse().unwrap();
+		assert_eq!(weekly.next_after(&at("2024-03-12T13:00:00Z")), at("2024-03-14T12:30:00Z"));
+		assert_eq!(weekly.next_after(&at("2024-03-14T13:00:00Z")), at("2024-03-18T12:30:00Z"));
+	}
+
+	#[test]
+	fn keep_delay_until_run() {
+		let dir = mktemp::Temp::new_dir().unwrap();
+		let mut scheduler = Scheduler::new(&dir);
+		let schedule = Schedule {
+			calendar: "daily 03:00".parse().unwrap(),
+			randomized_delay: std::time::Duration::from_secs(3600),
+			target: RunTo::Switch,
+		};
+		let now = at("2024-03-12T02:00:00Z").with_timezone(&Local);
+		scheduler.plan(Some(&schedule), now);
+		let next = scheduler.next_run().unwrap();
+		assert!(next >= at("2024-03-12T03:00:00Z") && next <= at("2024-03-12T04:00:00Z"));
+
+		// planning again, e.g. after a failed start or a config reload
+		scheduler.plan(Some(&schedule), next + Duration::minutes(5));
+		assert_eq!(scheduler.next_run(), Some(next));
+
+		scheduler.record_run(next + Duration::minutes(5)).unwrap();
+		scheduler.plan(Some(&schedule), next + Duration::minutes(5));
+		assert!(scheduler.next_run().unwrap() >= at("2024-03-13T03:00:00Z"));
+
+		scheduler.plan(None, now);
+		assert_eq!(scheduler.next_run(), None);
+	}
+}
@@ -0,0 +1,127 @@
+use crate::nix::store::StorePath;
+use serde::{Deserialize, Serialize};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use std::{fs, io};
+
+/// A system that was made the boot default but has not proven to work yet.
+#[derive(Debug, PartialEq, Serialize, Deserialize)]
+pub struct PendingBoot {
+	pub system: StorePath,
+	/// system to fall back to if `system` is not confirmed
+	pub previous: StorePath,
+	/// how often the daemon started in `system` without confirming it
+	pub attempts: u32,
+}
+
+/// Boots new systems on trial: they have to be confirmed after booting,
+/// otherwise the previous system is made the boot default again.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TrialBoot {
+	/// time after the daemon started until the new system must be confirmed
+	pub deadline: Duration,
+	/// boots of the new system after which it is given up
+	pub max_attempts: u32,
+	state_file: PathBuf,
+}
+
+impl TrialBoot {
+	const STATE_FILE: &'static str = "pending-boot.json";
+
+	pub fn new(state_dir: &Path, deadline: Duration, max_attempts: u32) -> Self {
+		Self {
+			deadline,
+			max_attempts,
+			state_file: state_dir.join(Self::STATE_FILE),
+		}
+	}
+
+	fn load(&self) -> io::Result<Option<PendingBoot>> {
+		match fs::read_to_string(&self.state_file) {
+			Ok(s) => Ok(Some(serde_json::from_str(&s)?)),
+			Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+			Err(e) => Err(e),
+		}
+	}
+
+	fn save(&self, pending: &PendingBoot) -> io::Result<()> {
+		if let Some(dir) = self.state_file.parent() {
+			fs::create_dir_all(dir)?;
+		}
+		fs::write(&self.state_file, serde_json::to_string(pending)?)
+	}
+
+	/// Remembers that `system` has to be confirmed after the next boot.
+	pub fn record(&self, system: &StorePath, previous: &StorePath) -> io::Result<()> {
+		self.save(&PendingBoot {
+			system: system.clone(),
+			previous: previous.clone(),
+			attempts: 0,
+		})
+	}
+
+	/// Counts a boot of `booted`, returns the pending boot if `booted` is on trial.
+	///
+	/// A pending boot of another system is dropped, the boot loader or the user picked something else.
+	pub fn start_attempt(&self, booted: &StorePath) -> io::Result<Option<PendingBoot>> {
+		let Some(mut pending) = self.load()? else {
+			return Ok(None);
+		};
+		if &pending.system != booted {
+			log::info!("booted {} instead of {}, not confirming it", booted, pending.system);
+			self.clear()?;
+			return Ok(None);
+		}
+		pending.attempts += 1;
+		self.save(&pending)?;
+		Ok(Some(pending))
+	}
+
+	/// Forgets the pending boot once it is confirmed or given up.
+	pub fn clear(&self) -> io::Result<()> {
+		match fs::remove_file(&self.state_file) {
+			Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+			_ => Ok(()),
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use mktemp::Temp;
+
+	#[test]
+	fn count_attempts() {
+		let dir = Temp::new_dir().unwrap();
+		let trial = TrialBoot::new(&dir, Duration::from_secs(60), 2);
+		let new: StorePath = "/nix/store/rnxji3jf6fb0nx2v0svdqpj9ml53gyqh-nixos-system-flink-24.05".parse().unwrap();
+		let old: StorePath = "/nix/store/0c3a1b5e2f9nx2v0svdqpj9ml53gyqh-nixos-system-flink-23.11".parse().unwrap();
+
+		assert_eq!(trial.start_attempt(&new).unwrap(), None);
+		trial.record(&new, &old).unwrap();
+		assert_eq!(trial.start_attempt(&new).unwrap().unwrap().attempts, 1);
+		let pending = trial.start_attempt(&new).unwrap().unwrap();
+		assert_eq!(pending.attempts, 2);
+		assert_eq!(pending.previous, old);
+
+		assert_eq!(trial.start_attempt(&old).unwrap(), None);
+		assert_eq!(trial.start_attempt(&new).unwrap(), None);
+	}
+
+	#[test]
+	fn survive_boot_into_recorded_system() {
+		let dir = Temp::new_dir().unwrap();
+		let new: StorePath = "/nix/store/rnxji3jf6fb0nx2v0svdqpj9ml53gyqh-nixos-system-flink-24.05".parse().unwrap();
+		let old: StorePath = "/nix/store/0c3a1b5e2f9nx2v0svdqpj9ml53gyqh-nixos-system-flink-23.11".parse().unwrap();
+		TrialBoot::new(&dir, Duration::from_secs(60), 2).record(&new, &old).unwrap();
+
+		// the daemon started again after booting the recorded system
+		let trial = TrialBoot::new(&dir, Duration::from_secs(60), 2);
+		let booted: StorePath = new.to_string().parse().unwrap();
+		let pending = trial.start_attempt(&booted).unwrap().unwrap();
+		assert_eq!(pending, PendingBoot { system: new.clone(), previous: old, attempts: 1 });
+		assert!(dir.join(TrialBoot::STATE_FILE).exists());
+		assert_eq!(trial.start_attempt(&booted).unwrap().unwrap().attempts, 2);
+	}
+}
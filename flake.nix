@@ -14,7 +14,14 @@
 			pkgs = import nixpkgs { inherit system; };
 			naersk = pkgs.callPackage naersk {};
 		in {
-			packages.default = naersk.buildPackage ./daemon ;
+			packages.default = naersk.buildPackage {
+				src = ./daemon;
+				postInstall = ''
+					install -Dm644 data/de.afuchs.NixOSUpdater.conf $out/share/dbus-1/system.d/de.afuchs.NixOSUpdater.conf
+					install -Dm644 data/de.afuchs.NixOSUpdater.policy $out/share/polkit-1/actions/de.afuchs.NixOSUpdater.policy
+					install -Dm644 interface/de.afuchs.NixOSUpdater.xml $out/share/dbus-1/interfaces/de.afuchs.NixOSUpdater.xml
+				'';
+			};
 			devShells.default = with pkgs; mkShell {
 				buildInputs = [
 					fenix.packages.${system}.latest.toolchain pre-commit
@@ -0,0 +1,37 @@
+use gtk::{gio, glib};
+use nixos_updater_interface::{self as interface, NixOSUpdater};
+use nixos_updater_interface::dbus::{self, blocking::{Connection, Proxy}};
+
+/// Daemon proxy as passed to [`call`].
+pub type DaemonProxy<'a> = Proxy<'a, &'a Connection>;
+
+/// Calls a method of the daemon in a worker thread, polkit may ask the user for a password meanwhile.
+pub fn call<F>(method: F)
+		where F: FnOnce(&DaemonProxy) -> Result<(), dbus::Error> + Send + 'static {
+	let handle = gio::spawn_blocking(move || {
+		let con = Connection::new_system()?;
+		method(&interface::proxy(&con))
+	});
+	glib::spawn_future_local(async move {
+		match handle.await {
+			Ok(Ok(())) => (),
+			Ok(Err(e)) => eprintln!("daemon call failed: {}", e),
+			Err(_) => eprintln!("daemon call panicked"),
+		}
+	});
+}
+
+/// Shows the daemon's state on the overview page every second.
+pub fn watch(page: &crate::ui::UpdaterOverviewPage) -> Result<(), dbus::Error> {
+	let con = Connection::new_system()?;
+	let version = interface::proxy(&con).interface_version()?;
+	if version != interface::INTERFACE_VERSION {
+		eprintln!("daemon speaks interface version {}, expected {}", version, interface::INTERFACE_VERSION);
+	}
+	let page = page.clone();
+	glib::timeout_add_seconds_local(1, move || {
+		page.show_daemon_state(&interface::proxy(&con));
+		glib::ControlFlow::Continue
+	});
+	Ok(())
+}
@@ -1,9 +1,11 @@
+mod daemon;
 mod ui;
 
 use gtk::prelude::*;
 use gtk::glib; 
 use adw::{Application, ApplicationWindow};
 use gio::*;
+use nixos_updater_interface::NixOSUpdater;
 
 const APP_ID: &str = "de.afuchs.NixOSUpdater";
 
@@ -17,6 +19,11 @@ fn main() -> glib::ExitCode {
     // Connect to "activate" signal of `app`
     app.connect_activate(activate);
 
+    let check = gio::ActionEntry::builder("check-for-updates")
+        .activate(|_: &Application, _, _| daemon::call(|p| p.check()))
+        .build();
+    app.add_action_entries([check]);
+
     // Run the application
     app.run()
 
@@ -33,6 +40,9 @@ fn push_example(app: &Application) {
 fn activate(app: &Application) {
     // Create a window and set the title
     let window = ui::UpdaterWindow::new(app);
+	 if let Err(e) = daemon::watch(&window.overview()) {
+		  eprintln!("could not connect to the daemon: {}", e);
+	 }
 	 window.present();
 
 }
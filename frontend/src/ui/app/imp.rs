@@ -7,6 +7,8 @@ use gtk::{glib, Button, CompositeTemplate};
 #[derive(CompositeTemplate, Default)]
 #[template(resource = "/de/afuchs/NixOSUpdater/app.ui")]
 pub struct UpdaterWindow {
+	#[template_child]
+	pub overview: TemplateChild<crate::ui::UpdaterOverviewPage>,
 //	#[template_child]
 //	pub button: TemplateChild<Button>,
 }
@@ -3,6 +3,7 @@ mod imp;
 use glib::Object;
 use gtk::{gio, glib};
 use adw::Application;
+use gtk::subclass::prelude::*;
 
 glib::wrapper! {
 	 pub struct UpdaterWindow(ObjectSubclass<imp::UpdaterWindow>)
@@ -16,5 +17,9 @@ impl UpdaterWindow {
         // Create new window
         Object::builder().property("application", app).build()
     }
+
+    pub fn overview(&self) -> crate::ui::UpdaterOverviewPage {
+        self.imp().overview.get()
+    }
 }
 
@@ -1,11 +1,28 @@
 use glib::subclass::InitializingObject;
+use std::cell::RefCell;
+use std::rc::Rc;
 use gtk::prelude::*;
 use adw::subclass::prelude::*;
-use gtk::{glib, Button, CompositeTemplate};
+use nixos_updater_interface::NixOSUpdater;
+use gtk::{glib, Button, CompositeTemplate, ListBoxRow, ProgressBar, TemplateChild};
 
 #[derive(CompositeTemplate, Default)]
 #[template(resource = "/de/afuchs/NixOSUpdater/overview.ui")]
 pub struct UpdaterOverviewPage {
+	#[template_child(id = "update-row")]
+	pub update_row: TemplateChild<adw::ExpanderRow>,
+	/// one row per changed input, inside `update_row`
+	pub input_rows: RefCell<Vec<adw::ActionRow>>,
+	#[template_child(id = "progress-row")]
+	pub progress_row: TemplateChild<ListBoxRow>,
+	#[template_child(id = "build-progress")]
+	pub build_progress: TemplateChild<ProgressBar>,
+	#[template_child(id = "button-switch")]
+	pub button_switch: TemplateChild<Button>,
+	#[template_child(id = "button-boot")]
+	pub button_boot: TemplateChild<Button>,
+	/// UpdateState shown last, in "ready" the buttons apply the kept build instead of updating again
+	pub update_state: Rc<RefCell<String>>,
 }
 
 // The central trait for subclassing a GObject
@@ -30,6 +47,19 @@ impl ObjectImpl for UpdaterOverviewPage {
 	fn constructed(&self) {
 		// Call "constructed" on parent
 		self.parent_constructed();
+
+		let state = Rc::clone(&self.update_state);
+		self.button_switch.connect_clicked(move |_| if *state.borrow() == "ready" {
+			crate::daemon::call(|p| p.apply_update("switch"))
+		} else {
+			crate::daemon::call(|p| p.switch())
+		});
+		let state = Rc::clone(&self.update_state);
+		self.button_boot.connect_clicked(move |_| if *state.borrow() == "ready" {
+			crate::daemon::call(|p| p.apply_update("set_boot"))
+		} else {
+			crate::daemon::call(|p| p.set_boot())
+		});
 	}
 }
 
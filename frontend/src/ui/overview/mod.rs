@@ -1,7 +1,10 @@
 mod imp;
 
 use glib::Object;
+use adw::prelude::*;
+use gtk::subclass::prelude::*;
 use gtk::{gio, glib};
+use nixos_updater_interface::NixOSUpdater;
 
 glib::wrapper! {
 	 pub struct UpdaterOverviewPage(ObjectSubclass<imp::UpdaterOverviewPage>)
@@ -19,6 +22,91 @@ impl UpdaterOverviewPage {
 	 fn new() -> Self {
 		  Object::builder().build()
 	 }
+
+	 /// Shows the daemon's UpdateState and what belongs to it, enables the buttons
+	 /// once there is an update to apply.
+	 pub fn show_daemon_state(&self, daemon: &impl NixOSUpdater) {
+		  let imp = self.imp();
+		  let state = match daemon.update_state() {
+				Ok(s) => s,
+				Err(e) => {
+					 imp.update_row.set_title("Aktualisierungsdienst nicht erreichbar");
+					 imp.update_row.set_subtitle(e.message().unwrap_or_default());
+					 return;
+				},
+		  };
+		  let title = match state.as_str() {
+				"up_to_date" => "System ist aktuell",
+				"processing" => "Systemaktualisierung wird vorbereitet",
+				"available" => "Systemaktualisierung verfügbar",
+				"ready" => "Systemaktualisierung bereit",
+				_ => "Systemaktualisierung fehlgeschlagen",
+		  };
+		  imp.update_row.set_title(title);
+		  let subtitle = match state.as_str() {
+				"processing" => daemon.process_state().unwrap_or_default(),
+				"ready" => daemon.reboot_reasons().unwrap_or_default().join(", "),
+				"error" => daemon.last_error().ok().flatten()
+					 .map_or_else(|| daemon.update_error().unwrap_or_default(), |e| e.message),
+				_ => String::new(),
+		  };
+		  imp.update_row.set_subtitle(&subtitle);
+		  self.set_progress(daemon.build_progress().ok().filter(|_| state == "processing"));
+		  self.set_input_changes(&daemon.input_changes().unwrap_or_default());
+		  let applicable = state == "available" || state == "ready";
+		  imp.button_switch.set_sensitive(applicable);
+		  imp.button_boot.set_sensitive(applicable);
+		  imp.update_state.replace(state);
+	 }
+
+	 /// Lists the daemon's InputChanges property in the update row:
+	 /// name, old and new revision, old and new last modification and URL.
+	 pub fn set_input_changes(&self, changes: &[(String, String, String, i64, i64, String)]) {
+		  let imp = self.imp();
+		  for row in imp.input_rows.take() {
+				imp.update_row.remove(&row);
+		  }
+		  let short = |rev: &str| if rev.is_empty() { "∅".to_string() } else { rev.chars().take(7).collect() };
+		  let date = |t: i64| glib::DateTime::from_unix_utc(t).ok()
+				.filter(|_| t != 0)
+				.and_then(|d| d.format("%Y-%m-%d").ok())
+				.map_or("∅".to_string(), |d| d.to_string());
+		  let mut rows = Vec::new();
+		  for (name, old_rev, new_rev, old_modified, new_modified, url) in changes {
+				let row = adw::ActionRow::builder()
+					 .title(format!("{}: {} → {}", name, short(old_rev), short(new_rev)))
+					 .subtitle(format!("{} → {}", date(*old_modified), date(*new_modified)))
+					 .tooltip_text(url.as_str())
+					 .build();
+				imp.update_row.add_row(&row);
+				rows.push(row);
+		  }
+		  imp.update_row.set_enable_expansion(! rows.is_empty());
+		  imp.input_rows.replace(rows);
+	 }
+
+	 /// Shows the daemon's BuildProgress property, None hides the progress bar.
+	 pub fn set_progress(&self, progress: Option<(u64, u64, u64, u64, String)>) {
+		  let imp = self.imp();
+		  let Some((done, expected, downloaded, download_size, current)) = progress else {
+				imp.progress_row.set_visible(false);
+				return;
+		  };
+		  imp.progress_row.set_visible(true);
+		  if expected > 0 {
+				imp.build_progress.set_fraction(done as f64 / expected as f64);
+		  } else if download_size > 0 {
+				imp.build_progress.set_fraction(downloaded as f64 / download_size as f64);
+		  } else {
+				imp.build_progress.pulse();
+		  }
+		  let text = if current.is_empty() {
+				format!("{} von {} Paketen gebaut", done, expected)
+		  } else {
+				format!("{} von {} Paketen gebaut, baue {}", done, expected, current)
+		  };
+		  imp.build_progress.set_text(Some(&text));
+	 }
 }
 
 